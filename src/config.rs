@@ -1,20 +1,614 @@
-/// The configuration file format
-//use clap::{ArgAction, Parser};
+//! Command line arguments and the configuration file format
+//!
+//! Settings are layered: built-in defaults, then the configuration file, then
+//! environment variables, then command line arguments.
+use anyhow::{anyhow, bail, Context, Result};
 use argh::FromArgs;
+use log::LevelFilter;
+use mpdris2_rs::mpd::{self, ServerOptions};
+#[cfg(feature = "notification")]
+use mpdris2_rs::plugins::fdo_notification::{
+    NotificationSettings, DEFAULT_MPD_ICON_PATH, DEFAULT_TIMEOUT,
+};
+#[cfg(feature = "mpris")]
+use mpdris2_rs::plugins::mpris2::{
+    self, OfflinePolicy, TagMapping, DEFAULT_BUS_NAME, DEFAULT_IDENTITY,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, env, fmt::Display, path::PathBuf, str::FromStr, time::Duration};
 
 #[derive(FromArgs, Debug)]
 /// A daemon to expose MPRIS V2.1 D-Bus interface for mpd
 pub struct Args {
+    /// path to the configuration file (Default: $XDG_CONFIG_HOME/mpdris2-rs/config.toml)
+    #[argh(option)]
+    pub config: Option<PathBuf>,
     /// address of MPD server (Default: localhost)
-    #[argh(option, default = "String::from(\"localhost\")")]
-    pub host: String,
+    #[argh(option)]
+    pub host: Option<String>,
     /// port of MPD server (Default: 6600)
-    #[argh(option, default = "6600")]
-    pub port: u32,
-    /// disable notification
+    #[argh(option)]
+    pub port: Option<u16>,
+    /// disable notification (same as `--disable-plugin notification`)
     #[argh(switch)]
     pub no_notification: bool,
+    /// disable a plugin (`mpris` or `notification`), can be repeated
+    #[argh(option)]
+    pub disable_plugin: Vec<String>,
+    /// what to do while MPD is unreachable: `stopped` or `release` (Default: stopped)
+    #[cfg(feature = "mpris")]
+    #[argh(option)]
+    pub offline_policy: Option<OfflinePolicy>,
+    /// print the effective configuration and exit
+    #[argh(switch)]
+    pub print_config: bool,
+    /// check the configuration and exit
+    #[argh(switch)]
+    pub check_config: bool,
     /// verbose
     #[argh(switch, short = 'v')]
     pub verbose: u8,
 }
+
+const MIB: u64 = 1024 * 1024;
+
+/// Plugins that can be enabled or disabled
+pub const PLUGINS: &[&str] = &["mpris", "notification"];
+
+/// The configuration file
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// `error`, `warn`, `info`, `debug` or `trace`
+    pub log_level: String,
+    pub connection: ConnectionConfig,
+    #[cfg(feature = "mpris")]
+    pub mpris: MprisConfig,
+    #[cfg(feature = "notification")]
+    pub notifications: NotificationConfig,
+    pub album_art: AlbumArtConfig,
+    pub lyrics: LyricsConfig,
+    pub plugins: PluginConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_level: String::from("info"),
+            connection: ConnectionConfig::default(),
+            #[cfg(feature = "mpris")]
+            mpris: MprisConfig::default(),
+            #[cfg(feature = "notification")]
+            notifications: NotificationConfig::default(),
+            album_art: AlbumArtConfig::default(),
+            lyrics: LyricsConfig::default(),
+            plugins: PluginConfig::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    pub host: String,
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// In seconds
+    pub ping_interval: u64,
+    /// In seconds
+    pub retry_interval: u64,
+    /// Asked from MPD if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub music_directory: Option<PathBuf>,
+    /// The default partition if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partition: Option<String>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        let options = ServerOptions::default();
+        ConnectionConfig {
+            host: String::from("localhost"),
+            port: 6600,
+            password: None,
+            ping_interval: options.ping_interval.as_secs(),
+            retry_interval: options.retry_interval.as_secs(),
+            music_directory: None,
+            partition: None,
+        }
+    }
+}
+
+#[cfg(feature = "mpris")]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MprisConfig {
+    pub bus_name: String,
+    pub identity: String,
+    pub offline_policy: OfflinePolicy,
+    /// Applied right away on reload, unlike the rest
+    pub tags: Vec<TagMapping>,
+    /// Other MPD partitions that get a player of their own
+    pub partitions: Vec<String>,
+}
+
+#[cfg(feature = "mpris")]
+impl Default for MprisConfig {
+    fn default() -> Self {
+        MprisConfig {
+            bus_name: DEFAULT_BUS_NAME.to_owned(),
+            identity: DEFAULT_IDENTITY.to_owned(),
+            offline_policy: OfflinePolicy::default(),
+            tags: Vec::new(),
+            partitions: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "notification")]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// In milliseconds
+    pub timeout: u32,
+    pub icon: String,
+}
+
+#[cfg(feature = "notification")]
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig {
+            timeout: DEFAULT_TIMEOUT,
+            icon: DEFAULT_MPD_ICON_PATH.to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlbumArtConfig {
+    pub enabled: bool,
+    pub directory: PathBuf,
+    /// In MiB
+    pub max_size: u64,
+    pub url: ArtUrl,
+    /// Only used with `url = "http"`, 0 for any free port
+    pub http_port: u16,
+    pub cover_names: Vec<String>,
+    /// Stream URL to image file
+    pub station_logos: HashMap<String, PathBuf>,
+}
+
+/// What kind of album art URLs to hand out
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtUrl {
+    /// Paths in the cache, for clients that can read our files
+    File,
+    /// Served on 127.0.0.1, for sandboxed clients
+    Http,
+}
+
+impl FromStr for ArtUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(ArtUrl::File),
+            "http" => Ok(ArtUrl::Http),
+            _ => Err(format!(
+                "unknown album art URL kind {s}, expect file or http"
+            )),
+        }
+    }
+}
+
+impl Default for AlbumArtConfig {
+    fn default() -> Self {
+        AlbumArtConfig {
+            enabled: true,
+            directory: mpd::default_album_art_dir(),
+            max_size: ServerOptions::default().album_art_max_size / MIB,
+            url: ArtUrl::File,
+            http_port: 0,
+            cover_names: ServerOptions::default().cover_names,
+            station_logos: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LyricsConfig {
+    pub enabled: bool,
+}
+
+impl Default for LyricsConfig {
+    fn default() -> Self {
+        LyricsConfig { enabled: true }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginConfig {
+    pub disabled: Vec<String>,
+}
+
+impl Config {
+    /// Build the effective configuration out of every layer
+    pub fn load(args: &Args) -> Result<Self> {
+        Config::layered(args, |name| env::var(name).ok())
+    }
+
+    /// Like `load`, with environment variables looked up by `var`
+    fn layered(args: &Args, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => match default_path() {
+                Some(path) if path.is_file() => Config::from_file(&path)?,
+                _ => Config::default(),
+            },
+        };
+        config.apply_env(var)?;
+        config.apply_args(args);
+        Ok(config)
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// `MPD_HOST` and `MPD_PORT`, the same variables mpc uses, and
+    /// `MPDRIS2_<SECTION>_<KEY>` for the other settings that hold a single
+    /// value. `var` looks variables up.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        let env = Env(var);
+        if let Some(host) = env.get::<String>("MPD_HOST")? {
+            // `password@host`, passwords may contain `@` but hosts don't
+            let (password, host) = match host.rsplit_once('@') {
+                Some((password, host)) => (Some(password), host),
+                None => (None, host.as_str()),
+            };
+            if let Some(password) = password.filter(|p| !p.is_empty()) {
+                self.connection.password = Some(password.to_owned());
+            }
+            if !host.is_empty() {
+                self.connection.host = host.to_owned();
+            }
+        }
+        env.set("MPD_PORT", &mut self.connection.port)?;
+
+        env.set("MPDRIS2_LOG_LEVEL", &mut self.log_level)?;
+        let connection = &mut self.connection;
+        env.set(
+            "MPDRIS2_CONNECTION_PING_INTERVAL",
+            &mut connection.ping_interval,
+        )?;
+        env.set(
+            "MPDRIS2_CONNECTION_RETRY_INTERVAL",
+            &mut connection.retry_interval,
+        )?;
+        if let Some(dir) = env.get("MPDRIS2_CONNECTION_MUSIC_DIRECTORY")? {
+            connection.music_directory = Some(dir);
+        }
+        if let Some(partition) = env.get("MPDRIS2_CONNECTION_PARTITION")? {
+            connection.partition = Some(partition);
+        }
+        #[cfg(feature = "mpris")]
+        {
+            let mpris = &mut self.mpris;
+            env.set("MPDRIS2_MPRIS_BUS_NAME", &mut mpris.bus_name)?;
+            env.set("MPDRIS2_MPRIS_IDENTITY", &mut mpris.identity)?;
+            env.set("MPDRIS2_MPRIS_OFFLINE_POLICY", &mut mpris.offline_policy)?;
+        }
+        #[cfg(feature = "notification")]
+        {
+            let notifications = &mut self.notifications;
+            env.set("MPDRIS2_NOTIFICATIONS_TIMEOUT", &mut notifications.timeout)?;
+            env.set("MPDRIS2_NOTIFICATIONS_ICON", &mut notifications.icon)?;
+        }
+        let album_art = &mut self.album_art;
+        env.set("MPDRIS2_ALBUM_ART_ENABLED", &mut album_art.enabled)?;
+        env.set("MPDRIS2_ALBUM_ART_DIRECTORY", &mut album_art.directory)?;
+        env.set("MPDRIS2_ALBUM_ART_MAX_SIZE", &mut album_art.max_size)?;
+        env.set("MPDRIS2_ALBUM_ART_URL", &mut album_art.url)?;
+        env.set("MPDRIS2_ALBUM_ART_HTTP_PORT", &mut album_art.http_port)?;
+        env.set("MPDRIS2_LYRICS_ENABLED", &mut self.lyrics.enabled)?;
+        if let Some(disabled) = env.get::<String>("MPDRIS2_PLUGINS_DISABLED")? {
+            // Comma separated
+            self.plugins.disabled = (disabled.split(','))
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(host) = &args.host {
+            self.connection.host.clone_from(host);
+        }
+        if let Some(port) = args.port {
+            self.connection.port = port;
+        }
+        #[cfg(feature = "mpris")]
+        if let Some(offline_policy) = args.offline_policy {
+            self.mpris.offline_policy = offline_policy;
+        }
+        let no_notification = args.no_notification.then(|| "notification".to_owned());
+        for name in args.disable_plugin.iter().cloned().chain(no_notification) {
+            if !self.plugins.disabled.contains(&name) {
+                self.plugins.disabled.push(name);
+            }
+        }
+    }
+
+    /// Look for mistakes that can't be caught while parsing
+    pub fn validate(&self) -> Result<()> {
+        LevelFilter::from_str(&self.log_level)
+            .with_context(|| format!("invalid log_level {}", self.log_level))?;
+        if self.connection.host.is_empty() {
+            bail!("connection.host can't be empty");
+        }
+        if self.connection.port == 0 {
+            bail!("connection.port can't be 0");
+        }
+        if (self.connection.password.as_deref()).is_some_and(|p| p.contains(['\n', '\r'])) {
+            bail!("connection.password can't contain line breaks");
+        }
+        if self.connection.ping_interval == 0 {
+            bail!("connection.ping_interval can't be 0");
+        }
+        if self.connection.retry_interval == 0 {
+            bail!("connection.retry_interval can't be 0");
+        }
+        if self.album_art.enabled && !self.album_art.directory.is_absolute() {
+            bail!("album_art.directory must be an absolute path");
+        }
+        if self.album_art.max_size.checked_mul(MIB).is_none() {
+            bail!("album_art.max_size is too large");
+        }
+        if let Some((url, _)) =
+            (self.album_art.station_logos.iter()).find(|(_, p)| !p.is_absolute())
+        {
+            bail!("album_art.station_logos: logo of {url} must be an absolute path");
+        }
+        if let Some(dir) = &self.connection.music_directory {
+            if !dir.is_absolute() {
+                bail!("connection.music_directory must be an absolute path");
+            }
+        }
+        if self.connection.partition.as_deref() == Some("") {
+            bail!("connection.partition can't be empty");
+        }
+        #[cfg(feature = "mpris")]
+        mpris2::check_bus_name(&self.mpris.bus_name).context("invalid mpris.bus_name")?;
+        #[cfg(feature = "mpris")]
+        for partition in &self.mpris.partitions {
+            if partition.is_empty() || Some(partition) == self.connection.partition.as_ref() {
+                bail!("invalid partition {partition:?} in mpris.partitions");
+            }
+            let bus_name = mpris2::partition_bus_name(&self.mpris.bus_name, partition);
+            mpris2::check_bus_name(&bus_name)
+                .with_context(|| format!("invalid bus name for partition {partition}"))?;
+        }
+        for name in &self.plugins.disabled {
+            if !PLUGINS.contains(&name.as_str()) {
+                bail!("unknown plugin {name} in plugins.disabled");
+            }
+        }
+        Ok(())
+    }
+
+    /// `-v` wins over the config file
+    pub fn log_level(&self, verbose: u8) -> LevelFilter {
+        match verbose {
+            0 => LevelFilter::from_str(&self.log_level).unwrap_or(LevelFilter::Info),
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    #[cfg(any(feature = "mpris", feature = "notification"))]
+    pub fn plugin_enabled(&self, name: &str) -> bool {
+        !self.plugins.disabled.iter().any(|p| p == name)
+    }
+
+    /// Settings that only take effect after a restart and differ from `other`
+    pub fn restart_needed(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        #[cfg(feature = "mpris")]
+        if (
+            &self.mpris.bus_name,
+            &self.mpris.identity,
+            self.mpris.offline_policy,
+            &self.mpris.partitions,
+        ) != (
+            &other.mpris.bus_name,
+            &other.mpris.identity,
+            other.mpris.offline_policy,
+            &other.mpris.partitions,
+        ) {
+            changed.push("mpris");
+        }
+        if self.plugins != other.plugins {
+            changed.push("plugins");
+        }
+        changed
+    }
+
+    #[cfg(feature = "notification")]
+    pub fn notification_settings(&self) -> NotificationSettings {
+        NotificationSettings {
+            timeout: self.notifications.timeout,
+            icon: self.notifications.icon.clone(),
+        }
+    }
+
+    pub fn server_options(&self) -> ServerOptions {
+        ServerOptions {
+            ping_interval: Duration::from_secs(self.connection.ping_interval),
+            retry_interval: Duration::from_secs(self.connection.retry_interval),
+            album_art: self.album_art.enabled,
+            album_art_dir: self.album_art.directory.clone(),
+            // Checked by `validate`
+            album_art_max_size: self.album_art.max_size.saturating_mul(MIB),
+            album_art_http: (self.album_art.url == ArtUrl::Http)
+                .then_some(self.album_art.http_port),
+            music_directory: self.connection.music_directory.clone(),
+            cover_names: self.album_art.cover_names.clone(),
+            station_logos: self.album_art.station_logos.clone(),
+            lyrics: self.lyrics.enabled,
+            password: self.connection.password.clone(),
+            partition: self.connection.partition.clone(),
+            shared_album_art: Default::default(),
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+/// Environment variables, looked up by a function
+struct Env<F>(F);
+
+impl<F: Fn(&str) -> Option<String>> Env<F> {
+    /// Parsed value of `name`, if it's set
+    fn get<T: FromStr>(&self, name: &str) -> Result<Option<T>>
+    where
+        T::Err: Display,
+    {
+        match (self.0)(name) {
+            Some(value) => (value.parse())
+                .map(Some)
+                .map_err(|e| anyhow!("Invalid {name} {value}: {e}")),
+            None => Ok(None),
+        }
+    }
+
+    /// Overwrite `field` with the value of `name`, if it's set
+    fn set<T: FromStr>(&self, name: &str, field: &mut T) -> Result<()>
+    where
+        T::Err: Display,
+    {
+        if let Some(value) = self.get(name)? {
+            *field = value;
+        }
+        Ok(())
+    }
+}
+
+/// `$XDG_CONFIG_HOME/mpdris2-rs/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("mpdris2-rs/config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::from_args(&["mpdris2-rs"], args).unwrap()
+    }
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        |name| {
+            (vars.iter())
+                .find(|(n, _)| *n == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn layers() {
+        let path = env::temp_dir().join(format!("mpdris2-rs-config-{}.toml", std::process::id()));
+        let file = "[connection]\nhost = \"file\"\nport = 6601\nping_interval = 30\n\
+                    [album_art]\nmax_size = 5\nenabled = false\n";
+        std::fs::write(&path, file).unwrap();
+        let path = path.to_str().unwrap();
+
+        // Defaults < file
+        let config = Config::layered(&args(&["--config", path]), env(&[])).unwrap();
+        assert_eq!(config.connection.host, "file");
+        assert_eq!(config.connection.port, 6601);
+        assert_eq!(config.connection.ping_interval, 30);
+        assert_eq!(config.connection.retry_interval, 5);
+        assert_eq!(config.album_art.max_size, 5);
+        assert_eq!(config.connection.password, None);
+
+        // File < environment < command line
+        let vars = [
+            ("MPD_HOST", "secret@env"),
+            ("MPD_PORT", "6602"),
+            ("MPDRIS2_CONNECTION_PING_INTERVAL", "20"),
+            ("MPDRIS2_ALBUM_ART_ENABLED", "true"),
+            ("MPDRIS2_ALBUM_ART_URL", "http"),
+            ("MPDRIS2_PLUGINS_DISABLED", "notification, mpris"),
+        ];
+        let config = Config::layered(&args(&["--config", path]), env(&vars)).unwrap();
+        assert_eq!(config.connection.host, "env");
+        assert_eq!(config.connection.password.as_deref(), Some("secret"));
+        assert_eq!(config.connection.port, 6602);
+        assert_eq!(config.connection.ping_interval, 20);
+        assert!(config.album_art.enabled);
+        assert_eq!(config.album_art.url, ArtUrl::Http);
+        assert_eq!(config.album_art.max_size, 5);
+        assert_eq!(config.plugins.disabled, ["notification", "mpris"]);
+
+        let cli = args(&["--config", path, "--host", "cli", "--port", "6603"]);
+        let config = Config::layered(&cli, env(&vars)).unwrap();
+        assert_eq!(config.connection.host, "cli");
+        assert_eq!(config.connection.password.as_deref(), Some("secret"));
+        assert_eq!(config.connection.port, 6603);
+        assert_eq!(config.connection.ping_interval, 20);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn environment() {
+        let apply = |vars: &[(&str, &str)]| {
+            let mut config = Config::default();
+            config.apply_env(env(vars)).map(|_| config)
+        };
+
+        // Passwords may contain `@`, and go without a host
+        let config = apply(&[("MPD_HOST", "p@ss@host")]).unwrap();
+        assert_eq!(config.connection.host, "host");
+        assert_eq!(config.connection.password.as_deref(), Some("p@ss"));
+        let config = apply(&[("MPD_HOST", "pass@")]).unwrap();
+        assert_eq!(config.connection.host, "localhost");
+        assert_eq!(config.connection.password.as_deref(), Some("pass"));
+        let config = apply(&[("MPD_HOST", "host")]).unwrap();
+        assert_eq!(config.connection.host, "host");
+        assert_eq!(config.connection.password, None);
+
+        assert!(apply(&[("MPD_PORT", "http")]).is_err());
+        assert!(apply(&[("MPDRIS2_ALBUM_ART_MAX_SIZE", "-1")]).is_err());
+        assert!(apply(&[("MPDRIS2_ALBUM_ART_URL", "ftp")]).is_err());
+        assert!(apply(&[("MPDRIS2_LYRICS_ENABLED", "yes")]).is_err());
+    }
+
+    #[test]
+    fn validation() {
+        assert!(Config::default().validate().is_ok());
+
+        let mut config = Config::default();
+        config.album_art.max_size = u64::MAX / MIB;
+        assert!(config.validate().is_ok());
+        config.album_art.max_size += 1;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.connection.password = Some("pass\nclose".to_owned());
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,28 @@
+//! Building blocks of mpdris2-rs, an MPD to MPRIS2 bridge.
+//!
+//! - [`mpd`] is an async MPD client, plus [`mpd::MpdStateServer`] which keeps
+//!   track of MPD state and broadcasts [`types::PlayerStateChange`] events.
+//! - [`plugins`] exposes that state over D-Bus: the MPRIS2 object and desktop
+//!   notifications. Only available with the `dbus` feature, which is enabled by
+//!   the default `mpris` and `notification` plugin features. Client-only users
+//!   can disable default features to leave out zbus entirely.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use mpdris2_rs::mpd::MpdStateServer;
+//!
+//! let server = MpdStateServer::init("localhost", 6600).await?;
+//! let mut events = server.get_mpd_event_rx();
+//! while let Ok(event) = events.recv().await {
+//!     println!("{event:?}: {}", server.get_status().playback_state);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+#![warn(missing_docs)]
+
+pub mod mpd;
+#[cfg(feature = "dbus")]
+pub mod plugins;
+pub mod supervisor;
+pub mod types;
@@ -1,19 +1,36 @@
 mod config;
-mod mpd;
-mod plugins;
-mod types;
+use config::Config;
 
-const RETRY_INTERVAL: Duration = Duration::from_secs(5);
-
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+#[cfg(any(feature = "mpris", feature = "notification"))]
+use arc_swap::ArcSwap;
 use colored::Colorize;
 use fern::colors::{Color, ColoredLevelConfig};
-use futures_util::stream::StreamExt;
-use log::{debug, error, info};
-use signal_hook::consts::signal::{SIGINT, SIGQUIT, SIGTERM};
+use futures_util::{future::join_all, stream::StreamExt};
+use log::{error, info, warn, LevelFilter};
+#[cfg(feature = "notification")]
+use mpdris2_rs::plugins::fdo_notification::{FdoNotificationRelay, NotificationSettings};
+use mpdris2_rs::{
+    mpd::MpdStateServer,
+    plugins::{self, Plugin, PluginHost, RunningPlugins},
+    supervisor::Supervisor,
+};
+#[cfg(feature = "mpris")]
+use mpdris2_rs::{
+    mpd::{ServerOptions, SharedAlbumArt},
+    plugins::mpris2::{self, Mpris2Builder, TagMapping},
+};
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1};
 use signal_hook_tokio::Signals;
-use std::{sync::Arc, time::Duration};
-use tokio::{runtime, sync::Mutex, time::sleep};
+#[cfg(any(feature = "mpris", feature = "notification"))]
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{runtime, select, time::timeout};
+
+/// Exit status when a critical task keeps failing
+const EXIT_CRITICAL_TASK_FAILED: i32 = 3;
+/// How long cleaning up may take before we exit anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn main() {
     let rt = match runtime::Builder::new_current_thread()
@@ -32,72 +49,277 @@ fn main() {
         err.chain().skip(1).for_each(|cause| {
             println!("{} {}", "DUE TO".yellow(), cause);
         });
+        std::process::exit(1);
     }
 }
 
 async fn try_main() -> Result<()> {
     let args: config::Args = argh::from_env();
-    setup_logger(args.verbose)?;
-
-    let mut first_retry = true;
-    let mpd_state_server = loop {
-        match mpd::MpdStateServer::init(&args.host, args.port).await {
-            Ok(c) => break c,
-            Err(e) => {
-                if first_retry {
-                    error!("Failed to connect to MPD server: {e}. Will try again every 5 secs...");
-                    first_retry = false;
-                } else {
-                    debug!("Retry failed.");
-                }
-                sleep(RETRY_INTERVAL).await;
-            }
-        }
-    };
-
-    let mpd_state_server = Arc::new(Mutex::new(mpd_state_server));
+    let config = Config::load(&args)?;
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    config.validate().context("Invalid configuration")?;
+    if args.check_config {
+        println!("Configuration is valid");
+        return Ok(());
+    }
+    setup_logger(config.log_level(args.verbose))?;
 
-    // Always need MPRIS2
-    let (connection, _notifier_task) = plugins::mpris2::start(mpd_state_server.clone()).await?;
+    let mut supervisor = Supervisor::new();
+    let options = config.server_options();
+    #[cfg(feature = "mpris")]
+    let album_art = options.shared_album_art.clone();
+    // Don't wait for MPD here, so that the bus name is claimed right away
+    let mpd_state_server = MpdStateServer::init_supervised(
+        &config.connection.host,
+        config.connection.port.into(),
+        options,
+        &supervisor,
+    )
+    .await?;
 
-    // Set up notification relay, if requested
-    let _notification_task = if !args.no_notification {
-        info!("Notification enabled, starting notification sender...");
-        let task = plugins::fdo_notification::start(&connection, mpd_state_server.clone()).await?;
-        Some(task)
-    } else {
-        info!("Notification disabled.");
-        None
+    let connection = plugins::session_connection().await?;
+    let mut plugin_host = PluginHost::new(mpd_state_server.clone(), connection);
+    let mut live = Live {
+        config,
+        server: mpd_state_server.clone(),
+        #[cfg(feature = "notification")]
+        notification: None,
+        #[cfg(feature = "mpris")]
+        tags: Vec::new(),
+        #[cfg(feature = "mpris")]
+        partitions: Vec::new(),
+        #[cfg(feature = "mpris")]
+        album_art,
     };
+    for plugin in load_plugins(&mut live)? {
+        plugin_host.add(plugin);
+    }
+    #[allow(unused_mut)]
+    let mut plugins = vec![plugin_host.start(&supervisor)];
+    #[cfg(feature = "mpris")]
+    plugins.extend(start_partitions(&mut live, &supervisor).await?);
 
     // Broadcast MPD server state change
-    mpd_state_server.lock().await.ready().await?;
+    if let Err(e) = mpd_state_server.ready().await {
+        error!("Failed to broadcast initial MPD state: {e}");
+    }
 
     // Now everything is set-up, wait for an exit signal
     info!("Service started.");
 
-    let mut signals = Signals::new([SIGTERM, SIGINT, SIGQUIT])?;
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGQUIT, SIGUSR1, SIGHUP])?;
     let handle = signals.handle();
-    if let Some(_signal) = signals.next().await {
-        info!("Exit signal received, closing D-Bus connection");
-        handle.close();
+    let exit_code = loop {
+        select! {
+            signal = signals.next() => match signal {
+                Some(SIGUSR1) => supervisor.report(),
+                Some(SIGHUP) => {
+                    info!("Reloading configuration");
+                    if let Err(e) = live.reload(&args).await {
+                        error!("Failed to reload configuration: {e:#}");
+                    }
+                }
+                _ => {
+                    info!("Exit signal received, shutting down");
+                    break None;
+                }
+            },
+            Some(task) = supervisor.fatal() => {
+                error!("{task} can't be kept alive, exiting");
+                supervisor.report();
+                break Some(EXIT_CRITICAL_TASK_FAILED);
+            }
+        }
+    };
+    handle.close();
+
+    let shutdown = join_all(plugins.into_iter().map(RunningPlugins::shutdown));
+    if timeout(SHUTDOWN_TIMEOUT, shutdown).await.is_err() {
+        warn!(
+            "Shutdown didn't finish in {}s, exiting anyway",
+            SHUTDOWN_TIMEOUT.as_secs()
+        );
+    }
+    if let Some(code) = exit_code {
+        std::process::exit(code);
     }
 
     Ok(())
 }
 
-fn setup_logger(debug: u8) -> Result<()> {
+/// Everything that can be changed without a restart
+struct Live {
+    config: Config,
+    server: MpdStateServer,
+    #[cfg(feature = "notification")]
+    notification: Option<Arc<ArcSwap<NotificationSettings>>>,
+    #[cfg(feature = "mpris")]
+    tags: Vec<Arc<ArcSwap<Vec<TagMapping>>>>,
+    /// State servers of the players of `mpris.partitions`
+    #[cfg(feature = "mpris")]
+    partitions: Vec<(String, MpdStateServer)>,
+    /// Album art cache and server, shared by the servers of every partition
+    #[cfg(feature = "mpris")]
+    album_art: SharedAlbumArt,
+}
+
+impl Live {
+    /// Read the configuration again and apply it in place. The old
+    /// configuration is kept if the new one is invalid.
+    async fn reload(&mut self, args: &config::Args) -> Result<()> {
+        let config = Config::load(args)?;
+        config.validate()?;
+
+        log::set_max_level(config.log_level(args.verbose));
+        #[cfg(feature = "notification")]
+        if let Some(settings) = &self.notification {
+            settings.store(Arc::new(config.notification_settings()));
+        }
+        #[cfg(feature = "mpris")]
+        for tags in &self.tags {
+            tags.store(Arc::new(config.mpris.tags.clone()));
+        }
+        for section in config.restart_needed(&self.config) {
+            warn!("Changes in [{section}] take effect after a restart");
+        }
+
+        // Try every server, so that one failing doesn't leave the others on
+        // the old settings
+        let connection = &config.connection;
+        let mut errors = Vec::new();
+        let res = self
+            .server
+            .reconfigure(
+                &connection.host,
+                connection.port.into(),
+                config.server_options(),
+            )
+            .await;
+        if let Err(e) = res {
+            errors.push(format!("{e:#}"));
+        }
+        #[cfg(feature = "mpris")]
+        for (partition, server) in &self.partitions {
+            let res = server
+                .reconfigure(
+                    &connection.host,
+                    connection.port.into(),
+                    partition_options(&config, partition, &self.album_art),
+                )
+                .await;
+            if let Err(e) = res {
+                errors.push(format!("partition {partition}: {e:#}"));
+            }
+        }
+        // Keep comparing against the old configuration until it's all applied
+        if !errors.is_empty() {
+            bail!("{}", errors.join("; "));
+        }
+        self.config = config;
+        info!("Configuration reloaded");
+        Ok(())
+    }
+}
+
+/// Options for the state server of another partition
+#[cfg(feature = "mpris")]
+fn partition_options(
+    config: &Config,
+    partition: &str,
+    album_art: &SharedAlbumArt,
+) -> ServerOptions {
+    ServerOptions {
+        partition: Some(partition.to_owned()),
+        shared_album_art: album_art.clone(),
+        ..config.server_options()
+    }
+}
+
+/// Every plugin that is both compiled in and enabled
+#[cfg_attr(
+    not(any(feature = "mpris", feature = "notification")),
+    allow(unused_variables, unused_mut)
+)]
+fn load_plugins(live: &mut Live) -> Result<Vec<Box<dyn Plugin>>> {
+    #[cfg(any(feature = "mpris", feature = "notification"))]
+    let config = &live.config;
+    let mut plugins: Vec<Box<dyn Plugin>> = Vec::new();
+    #[cfg(feature = "mpris")]
+    if config.plugin_enabled("mpris") {
+        let mpris = Mpris2Builder::new()
+            .offline_policy(config.mpris.offline_policy)
+            .bus_name(&config.mpris.bus_name)
+            .identity(&config.mpris.identity)
+            .tags(config.mpris.tags.clone())
+            .build()?;
+        live.tags.push(mpris.tags_handle());
+        plugins.push(Box::new(mpris));
+    }
+    #[cfg(feature = "notification")]
+    if config.plugin_enabled("notification") {
+        let notification = FdoNotificationRelay::new().settings(config.notification_settings());
+        live.notification = Some(notification.settings_handle());
+        plugins.push(Box::new(notification));
+    }
+
+    for name in config::PLUGINS {
+        if !plugins.iter().any(|p| p.name() == *name) {
+            info!("Plugin {name} disabled.");
+        }
+    }
+    Ok(plugins)
+}
+
+/// Give every partition in `mpris.partitions` a MPRIS player of its own,
+/// with its own state server and bus connection
+#[cfg(feature = "mpris")]
+async fn start_partitions(live: &mut Live, supervisor: &Supervisor) -> Result<Vec<RunningPlugins>> {
+    let mut running = Vec::new();
+    if !live.config.plugin_enabled("mpris") {
+        return Ok(running);
+    }
+    for partition in live.config.mpris.partitions.clone() {
+        let config = &live.config;
+        let server = MpdStateServer::init_supervised(
+            &config.connection.host,
+            config.connection.port.into(),
+            partition_options(config, &partition, &live.album_art),
+            supervisor,
+        )
+        .await?;
+        let mpris = Mpris2Builder::new()
+            .offline_policy(config.mpris.offline_policy)
+            .bus_name(&mpris2::partition_bus_name(
+                &config.mpris.bus_name,
+                &partition,
+            ))
+            .identity(&format!("{} ({partition})", config.mpris.identity))
+            .tags(config.mpris.tags.clone())
+            .build()?;
+        live.tags.push(mpris.tags_handle());
+
+        // Every player needs its own connection, as they share the object path
+        let connection = plugins::session_connection().await?;
+        let mut host = PluginHost::new(server.clone(), connection).label(&partition);
+        host.add(Box::new(mpris));
+        running.push(host.start(supervisor));
+        if let Err(e) = server.ready().await {
+            error!("Failed to broadcast initial MPD state of partition {partition}: {e}");
+        }
+        live.partitions.push((partition, server));
+    }
+    Ok(running)
+}
+
+fn setup_logger(level: LevelFilter) -> Result<()> {
     let colors = ColoredLevelConfig::new()
         .error(Color::Red)
         .warn(Color::Yellow)
         .info(Color::Blue);
 
-    let level = match debug {
-        1 => log::LevelFilter::Debug,
-        2 => log::LevelFilter::Trace,
-        _ => log::LevelFilter::Info,
-    };
-
     fern::Dispatch::new()
         .format(move |out, message, record| {
             out.finish(format_args!(
@@ -107,8 +329,9 @@ fn setup_logger(debug: u8) -> Result<()> {
                 message
             ));
         })
-        .level(level)
         .chain(std::io::stdout())
         .apply()?;
+    // Let everything through fern, so that the level can be changed later
+    log::set_max_level(level);
     Ok(())
 }
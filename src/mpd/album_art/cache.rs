@@ -0,0 +1,379 @@
+//! Content-addressed album art cache on disk
+//!
+//! `objects/<sha256>.<ext>` holds the images, named after the hash of what MPD
+//! sent, next to their thumbnails `objects/<sha256>-thumb.<ext>`.
+//! `keys/<sha256 of album key>` holds the file name of the image of an album,
+//! or nothing if the album has no art. Modification times tell when something
+//! was last used, and art that's being shown is pinned so that it stays.
+use super::process::{process, Processed};
+use crate::mpd::types::AlbumArt;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use tokio::{fs, io::AsyncWriteExt, task::spawn_blocking};
+
+/// Extensions of the formats we save
+const EXTENSIONS: [&str; 2] = ["png", "jpg"];
+/// How long to believe that an album has no art
+const NO_ART_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// What the cache knows about an album
+pub enum Lookup {
+    /// Here's the album art
+    Hit(AlbumArt),
+    /// The album has no art, don't bother asking MPD again for now
+    NoArt,
+    /// Never seen this album
+    Miss,
+}
+
+/// Album art on disk, shared by every song of the same album
+pub struct AlbumArtCache {
+    dir: PathBuf,
+    max_size: u64,
+    // Art that's being shown, by who shows it
+    pinned: Mutex<HashMap<u64, AlbumArt>>,
+}
+
+impl AlbumArtCache {
+    /// Use `dir` as the cache, creating it if needed. The cache is evicted
+    /// down to `max_size` bytes, least recently used first.
+    pub async fn open(dir: &Path, max_size: u64) -> Result<Self> {
+        let cache = AlbumArtCache {
+            dir: dir.to_owned(),
+            max_size,
+            pinned: Mutex::default(),
+        };
+        for dir in [cache.dir.clone(), cache.objects(), cache.keys()] {
+            private_dir(&dir)
+                .await
+                .with_context(|| format!("Can't use {} for album art", dir.display()))?;
+        }
+        Ok(cache)
+    }
+
+    /// Look up the album art of `key`
+    pub async fn get(&self, key: &str) -> Result<Lookup> {
+        let key_path = self.key_path(key);
+        let meta = match fs::metadata(&key_path).await {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Lookup::Miss),
+            Err(e) => return Err(e.into()),
+        };
+        let name = fs::read_to_string(&key_path).await?;
+        let name = name.trim();
+        if name.is_empty() {
+            let age = meta.modified()?.elapsed().unwrap_or_default();
+            return Ok(if age < NO_ART_TTL {
+                Lookup::NoArt
+            } else {
+                Lookup::Miss
+            });
+        }
+        let Some((hash, ext)) = parse_name(name) else {
+            warn!("Corrupted album art cache entry {}", key_path.display());
+            return Ok(Lookup::Miss);
+        };
+
+        let art = self.paths(hash, ext);
+        if !is_file(&art.full).await || !is_file(&art.thumbnail).await {
+            // Evicted
+            return Ok(Lookup::Miss);
+        }
+        touch(&art).await;
+        Ok(Lookup::Hit(art))
+    }
+
+    /// Keep `art` from being evicted while `owner` shows it, in place of
+    /// what `owner` showed before. `None` lets go of it.
+    pub fn pin(&self, owner: u64, art: Option<&AlbumArt>) {
+        let mut pinned = self.pinned.lock().unwrap();
+        match art {
+            Some(art) => pinned.insert(owner, art.clone()),
+            None => pinned.remove(&owner),
+        };
+    }
+
+    /// Pin everything `other` has pinned, when taking over from it
+    pub fn pin_all(&self, other: &AlbumArtCache) {
+        let theirs = other.pinned.lock().unwrap().clone();
+        self.pinned.lock().unwrap().extend(theirs);
+    }
+
+    /// Remember the album art of `key`, or that it has none. Returns where
+    /// the art was saved.
+    pub async fn insert(&self, key: &str, data: Option<Vec<u8>>) -> Result<Option<AlbumArt>> {
+        let art = match data {
+            Some(data) => self.save(data).await?,
+            None => None,
+        };
+        let name = art
+            .as_ref()
+            .and_then(|art| art.full.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        write_private(&self.key_path(key), name.as_bytes()).await?;
+
+        if let Err(e) = self.evict(art.as_ref()).await {
+            warn!("Failed to evict album art cache: {e}");
+        }
+        Ok(art)
+    }
+
+    /// Save an image and its thumbnail, unless we already have them
+    async fn save(&self, data: Vec<u8>) -> Result<Option<AlbumArt>> {
+        let hash = format!("{:x}", Sha256::digest(&data));
+        for ext in EXTENSIONS {
+            let art = self.paths(&hash, ext);
+            if is_file(&art.full).await && is_file(&art.thumbnail).await {
+                touch(&art).await;
+                return Ok(Some(art));
+            }
+        }
+
+        let processed = match spawn_blocking(move || process(data)).await? {
+            Ok(processed) => processed,
+            Err(e) => {
+                // Asking again won't make it any better
+                warn!("Unusable album art: {e:#}");
+                return Ok(None);
+            }
+        };
+        let Processed {
+            ext,
+            full,
+            thumbnail,
+        } = processed;
+        let art = self.paths(&hash, ext);
+        write_private(&art.full, &full).await?;
+        write_private(&art.thumbnail, &thumbnail).await?;
+        debug!("Album art saved to {}", art.full.display());
+        Ok(Some(art))
+    }
+
+    /// Delete the least recently used images until the cache fits in
+    /// `max_size`. `keep` and pinned art are never deleted.
+    async fn evict(&self, keep: Option<&AlbumArt>) -> Result<()> {
+        let mut objects = Vec::new();
+        let mut total = 0;
+        let mut entries = fs::read_dir(self.objects()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                // Still being written
+                continue;
+            }
+            // Another process sharing the cache may have evicted it already
+            let meta = match entry.metadata().await {
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                res => res?,
+            };
+            total += meta.len();
+            objects.push((meta.modified()?, meta.len(), path));
+        }
+        if total <= self.max_size {
+            return Ok(());
+        }
+
+        objects.sort();
+        let mut kept: Vec<_> = self.pinned.lock().unwrap().values().cloned().collect();
+        kept.extend(keep.cloned());
+        for (_, len, path) in objects {
+            if total <= self.max_size {
+                break;
+            }
+            if kept
+                .iter()
+                .any(|art| path == art.full || path == art.thumbnail)
+            {
+                continue;
+            }
+            debug!("Evicting {} from album art cache", path.display());
+            match fs::remove_file(&path).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => total -= len,
+            }
+        }
+        self.prune_keys().await
+    }
+
+    /// Forget about albums whose art has been evicted
+    async fn prune_keys(&self) -> Result<()> {
+        let mut entries = fs::read_dir(self.keys()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = fs::read_to_string(entry.path()).await.unwrap_or_default();
+            let name = name.trim();
+            if !name.is_empty() && !is_file(&self.objects().join(name)).await {
+                fs::remove_file(entry.path()).await.ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the images are
+    pub fn objects(&self) -> PathBuf {
+        self.dir.join("objects")
+    }
+
+    fn keys(&self) -> PathBuf {
+        self.dir.join("keys")
+    }
+
+    fn paths(&self, hash: &str, ext: &str) -> AlbumArt {
+        AlbumArt {
+            full: self.objects().join(format!("{hash}.{ext}")),
+            thumbnail: self.objects().join(format!("{hash}-thumb.{ext}")),
+            url: None,
+        }
+    }
+
+    fn key_path(&self, key: &str) -> PathBuf {
+        self.keys().join(format!("{:x}", Sha256::digest(key)))
+    }
+}
+
+/// Create `dir` if needed, and make sure only we can get in
+async fn private_dir(dir: &Path) -> Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .await?;
+    let meta = fs::symlink_metadata(dir).await?;
+    if !meta.is_dir() {
+        bail!("not a directory");
+    }
+    if meta.permissions().mode() & 0o077 != 0 {
+        // Fails if someone else owns it
+        fs::set_permissions(dir, PermissionsExt::from_mode(0o700)).await?;
+    }
+    Ok(())
+}
+
+/// Write a file readable only by us. Readers never see a partial file.
+async fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{name}.tmp"));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .await?;
+    file.write_all(data).await?;
+    file.flush().await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+async fn is_file(path: &Path) -> bool {
+    fs::metadata(path).await.is_ok_and(|meta| meta.is_file())
+}
+
+/// Mark `art` as just used
+async fn touch(art: &AlbumArt) {
+    let paths = [art.full.clone(), art.thumbnail.clone()];
+    // Tokio can't set modification times
+    let res = spawn_blocking(move || {
+        for path in paths {
+            let res = std::fs::File::options()
+                .append(true)
+                .open(&path)
+                .and_then(|f| f.set_modified(SystemTime::now()));
+            if let Err(e) = res {
+                debug!("Failed to touch {}: {e}", path.display());
+            }
+        }
+    })
+    .await;
+    if let Err(e) = res {
+        debug!("Failed to touch album art: {e}");
+    }
+}
+
+/// Split `<hash>.<ext>`, making sure it can't point outside the cache
+fn parse_name(name: &str) -> Option<(&str, &str)> {
+    let (hash, ext) = name.split_once('.')?;
+    let is_hash = hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit());
+    (is_hash && EXTENSIONS.contains(&ext)).then_some((hash, ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 1x1 PNG, red or blue
+    fn png(blue: bool) -> Vec<u8> {
+        let mut png = vec![
+            0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48,
+            0x44, 0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x00, 0x00,
+            0x00, 0x90, 0x77, 0x53, 0xde, 0x00, 0x00, 0x00, 0x0c, 0x49, 0x44, 0x41, 0x54, 0x78,
+            0x9c, 0x63,
+        ];
+        png.extend_from_slice(if blue {
+            &[
+                0x60, 0x60, 0xf8, 0x0f, 0x00, 0x01, 0x03, 0x01, 0x00, 0x08, 0x89, 0xc2, 0xec,
+            ]
+        } else {
+            &[
+                0xf8, 0xcf, 0xc0, 0x00, 0x00, 0x03, 0x01, 0x01, 0x00, 0xc9, 0xfe, 0x92, 0xef,
+            ]
+        });
+        png.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+        ]);
+        png
+    }
+
+    #[tokio::test]
+    async fn pinning() {
+        let dir = std::env::temp_dir().join(format!("mpdris2-rs-cache-{}", std::process::id()));
+        // Room for one image and its thumbnail
+        let max_size = 3 * png(false).len() as u64;
+        let cached = |lookup| matches!(lookup, Lookup::Hit(_));
+
+        let cache = AlbumArtCache::open(&dir.join("lru"), max_size)
+            .await
+            .unwrap();
+        cache.insert("red", Some(png(false))).await.unwrap();
+        cache.insert("blue", Some(png(true))).await.unwrap();
+        assert!(!cached(cache.get("red").await.unwrap()));
+        assert!(cached(cache.get("blue").await.unwrap()));
+
+        let cache = AlbumArtCache::open(&dir.join("pinned"), max_size)
+            .await
+            .unwrap();
+        let red = cache.insert("red", Some(png(false))).await.unwrap();
+        cache.pin(1, red.as_ref());
+        cache.insert("blue", Some(png(true))).await.unwrap();
+        assert!(cached(cache.get("red").await.unwrap()));
+        assert!(cached(cache.get("blue").await.unwrap()));
+
+        // Pins survive the cache being opened again
+        let reopened = AlbumArtCache::open(&dir.join("pinned"), max_size)
+            .await
+            .unwrap();
+        reopened.pin_all(&cache);
+        let blue = reopened.get("blue").await.unwrap();
+        let Lookup::Hit(blue) = blue else {
+            panic!("blue album art was evicted");
+        };
+        // Red goes once it's not shown anymore
+        reopened.pin(1, Some(&blue));
+        reopened.evict(None).await.unwrap();
+        assert!(!cached(reopened.get("red").await.unwrap()));
+        assert!(cached(reopened.get("blue").await.unwrap()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
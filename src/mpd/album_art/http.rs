@@ -0,0 +1,307 @@
+//! Serving cached album art over HTTP on the loopback interface, for clients
+//! that can't read our files
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    select, spawn,
+    task::{AbortHandle, JoinSet},
+    time::{sleep, timeout},
+};
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a client may take to read the response
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait before accepting again after a failure
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+/// Longest request head we bother reading
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Album art server on 127.0.0.1. Stops when dropped, and so do the
+/// connections it's serving.
+pub struct ArtServer {
+    base_url: String,
+    task: AbortHandle,
+}
+
+impl ArtServer {
+    /// Serve the files in `dir` on `port`, or any free port if it's 0. Files
+    /// are only reachable through a secret prefix, see [`ArtServer::url`].
+    pub async fn start(dir: PathBuf, port: u16) -> Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+            .await
+            .with_context(|| format!("Can't listen on 127.0.0.1:{port}"))?;
+        let port = listener.local_addr()?.port();
+
+        let mut token = [0; 16];
+        getrandom::getrandom(&mut token).context("Can't generate album art URLs")?;
+        let token: String = token.iter().map(|b| format!("{b:02x}")).collect();
+
+        info!("Serving album art on 127.0.0.1:{port}");
+        let task = spawn(serve(listener, dir, format!("/{token}/"))).abort_handle();
+        Ok(ArtServer {
+            base_url: format!("http://127.0.0.1:{port}/{token}/"),
+            task,
+        })
+    }
+
+    /// URL of a file in the served directory
+    pub fn url(&self, path: &Path) -> Option<String> {
+        let name = path.file_name()?.to_str()?;
+        Some(format!("{}{name}", self.base_url))
+    }
+}
+
+impl Drop for ArtServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(listener: TcpListener, dir: PathBuf, prefix: String) {
+    // Aborted along with this task
+    let mut connections = JoinSet::new();
+    loop {
+        let accepted = select! {
+            accepted = listener.accept() => accepted,
+            // Forget about connections that are done
+            Some(_) = connections.join_next() => continue,
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                debug!("Failed to accept album art request: {e}");
+                // Out of file descriptors, most likely
+                sleep(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        let (dir, prefix) = (dir.clone(), prefix.clone());
+        connections.spawn(async move {
+            if let Err(e) = handle(stream, &dir, &prefix).await {
+                debug!("Failed to serve album art: {e}");
+            }
+        });
+    }
+}
+
+/// Answer a single request, then hang up
+async fn handle(stream: TcpStream, dir: &Path, prefix: &str) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let head = timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .unwrap_or_else(|_| bail!("request timed out"))?;
+    let mut words = head.split(' ');
+    let (method, target) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+
+    let (status, content, body) = if method != "GET" && method != "HEAD" {
+        ("405 Method Not Allowed", None, Vec::new())
+    } else {
+        let data = match target
+            .strip_prefix(prefix)
+            .filter(|name| is_file_name(name))
+        {
+            Some(name) => fs::read(dir.join(name)).await.ok().map(|data| (name, data)),
+            None => None,
+        };
+        match data {
+            Some((name, data)) => {
+                let content_type = if name.ends_with(".jpg") {
+                    "image/jpeg"
+                } else {
+                    "image/png"
+                };
+                let content = Some((content_type, data.len()));
+                let body = if method == "HEAD" { Vec::new() } else { data };
+                ("200 OK", content, body)
+            }
+            None => ("404 Not Found", None, Vec::new()),
+        }
+    };
+    timeout(
+        RESPONSE_TIMEOUT,
+        respond(stream.get_mut(), status, content, &body),
+    )
+    .await
+    .unwrap_or_else(|_| bail!("response timed out"))
+}
+
+/// The request line. Headers are read and ignored, and heads longer than
+/// [`MAX_REQUEST_SIZE`] are refused.
+async fn read_head(stream: impl AsyncBufRead + Unpin) -> Result<String> {
+    // One byte more, to tell a head that fits from one that doesn't
+    let mut stream = stream.take(MAX_REQUEST_SIZE as u64 + 1);
+    let mut request_line = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            bail!("bad request");
+        }
+        if stream.limit() == 0 {
+            bail!("request head too long");
+        }
+        if line.trim_end().is_empty() {
+            return Ok(request_line);
+        }
+        if request_line.is_empty() {
+            request_line = line.trim_end().to_owned();
+        }
+    }
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content: Option<(&str, usize)>,
+    body: &[u8],
+) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+    match content {
+        Some((content_type, len)) => {
+            // Files are named after their content, they never change
+            head += &format!(
+                "Content-Type: {content_type}\r\nContent-Length: {len}\r\n\
+                 Cache-Control: max-age=31536000, immutable\r\n\
+                 Access-Control-Allow-Origin: *\r\n"
+            );
+        }
+        None => head += "Content-Length: 0\r\n",
+    }
+    head += "\r\n";
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// A plain file name in the served directory, no way out of it
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Port and path of a URL of the server
+    fn split_url(url: &str) -> (&str, &str) {
+        let rest = url.strip_prefix("http://127.0.0.1:").unwrap();
+        rest.split_at(rest.find('/').unwrap())
+    }
+
+    /// Send `request` to `server` and read the whole response
+    async fn request(server: &ArtServer, request: &[u8]) -> String {
+        let (port, _) = split_url(&server.base_url);
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        // The server may hang up before reading everything
+        stream.read_to_end(&mut response).await.ok();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    async fn get(server: &ArtServer, method: &str, target: &str) -> String {
+        let head = format!("{method} {target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        request(server, head.as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn read_heads() {
+        let head = read_head(&b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nbody"[..]).await;
+        assert_eq!(head.unwrap(), "GET /a HTTP/1.1");
+        assert!(read_head(&b"GET /a HTTP/1.1\r\n"[..]).await.is_err());
+
+        let long = |len| format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(len));
+        let fits = long(MAX_REQUEST_SIZE - 23);
+        assert_eq!(fits.len(), MAX_REQUEST_SIZE);
+        assert!(read_head(fits.as_bytes()).await.is_ok());
+        let too_long = long(MAX_REQUEST_SIZE - 22);
+        assert!(read_head(too_long.as_bytes()).await.is_err());
+        // No line break at all
+        let endless = "a".repeat(10 * MAX_REQUEST_SIZE);
+        assert!(read_head(endless.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn serving() {
+        let dir = std::env::temp_dir().join(format!("mpdris2-rs-http-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("abc.png"), b"picture").unwrap();
+        std::fs::write(dir.join("sub/abc.png"), b"secret").unwrap();
+        std::fs::write(dir.join(".hidden.png"), b"secret").unwrap();
+
+        let server = ArtServer::start(dir.clone(), 0).await.unwrap();
+        let url = server.url(Path::new("/anywhere/abc.png")).unwrap();
+        let (port, path) = split_url(&url);
+        let token = path.split('/').nth(1).unwrap();
+
+        let response = get(&server, "GET", path).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: image/png\r\n"));
+        assert!(response.ends_with("\r\n\r\npicture"));
+        let response = get(&server, "HEAD", path).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 7\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+        assert!(get(&server, "POST", path)
+            .await
+            .starts_with("HTTP/1.1 405 "));
+
+        // Without the right token
+        for target in [
+            "/abc.png".to_owned(),
+            "/0123456789abcdef0123456789abcdef/abc.png".to_owned(),
+            format!("/{}/abc.png", &token[1..]),
+            format!("/{token}abc.png"),
+        ] {
+            let response = get(&server, "GET", &target).await;
+            assert!(response.starts_with("HTTP/1.1 404 "), "{target}");
+        }
+        // Outside of the directory, or not a plain file name
+        for name in [
+            "sub/abc.png",
+            "../abc.png",
+            "..%2fabc.png",
+            "%2e%2e/abc.png",
+            ".hidden.png",
+            "",
+            "missing.png",
+        ] {
+            let response = get(&server, "GET", &format!("/{token}/{name}")).await;
+            assert!(response.starts_with("HTTP/1.1 404 "), "{name}");
+        }
+
+        // Oversized heads get no answer
+        let mut head = format!("GET {path} HTTP/1.1\r\nX: ").into_bytes();
+        head.extend(vec![b'a'; 2 * MAX_REQUEST_SIZE]);
+        head.extend(b"\r\n\r\n");
+        assert_eq!(request(&server, &head).await, "");
+
+        // Connections in progress end with the server
+        let mut idle = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(50)).await;
+        drop(server);
+        let mut buf = Vec::new();
+        let read = timeout(Duration::from_secs(1), idle.read_to_end(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+//! Looking for album art in the music directory ourselves, for when MPD
+//! can't find any
+use super::process::is_image_name;
+use anyhow::{bail, Result};
+use id3::{frame::PictureType, Tag};
+use log::debug;
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+use wildmatch::WildMatch;
+
+/// Cover files looked for unless told otherwise, best first
+pub const DEFAULT_COVER_NAMES: [&str; 4] = ["cover.*", "folder.*", "front.*", "AlbumArt*"];
+
+/// FLAC metadata block holding a picture
+const FLAC_PICTURE: u8 = 6;
+/// FLAC picture type of the front cover
+const FRONT_COVER: u32 = 3;
+
+/// Where the music is, and which files are covers
+#[derive(Clone, Debug)]
+pub struct LocalArt {
+    music_dir: PathBuf,
+    cover_names: Vec<WildMatch>,
+    // Pictures bigger than this in bytes are left alone
+    max_size: u64,
+}
+
+impl LocalArt {
+    /// Look in `music_dir` for files matching `cover_names`, which may contain
+    /// `*` and `?` and are matched regardless of case. Pictures bigger than
+    /// `max_size` bytes, which wouldn't fit in the cache, are ignored.
+    pub fn new(music_dir: PathBuf, cover_names: &[String], max_size: u64) -> Self {
+        LocalArt {
+            music_dir,
+            cover_names: cover_names
+                .iter()
+                .map(|name| WildMatch::new_case_insensitive(name))
+                .collect(),
+            max_size,
+        }
+    }
+
+    /// Album art of `uri`: embedded in the file first, then a cover file next
+    /// to it. This reads files, don't run it on the runtime.
+    pub fn find(&self, uri: &str) -> Option<Vec<u8>> {
+        // Don't let MPD send us anywhere else
+        let relative = Path::new(uri);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return None;
+        }
+        let path = self.music_dir.join(relative);
+
+        match read_embedded(&path, self.max_size) {
+            Ok(Some(data)) => {
+                debug!("Found embedded album art in {}", path.display());
+                return Some(data);
+            }
+            Ok(None) => (),
+            Err(e) => debug!("Can't read album art from {}: {e}", path.display()),
+        }
+
+        let mut dir = path.parent()?;
+        loop {
+            if let Some(data) = self.read_cover_file(dir) {
+                return Some(data);
+            }
+            // The cover of a multi-disc album is usually next to the discs
+            match dir.parent() {
+                Some(parent) if is_disc_dir(dir) && parent.starts_with(&self.music_dir) => {
+                    dir = parent
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// The best matching cover file in `dir`
+    fn read_cover_file(&self, dir: &Path) -> Option<Vec<u8>> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| is_image_name(name))
+            .collect();
+        names.sort();
+
+        for pattern in &self.cover_names {
+            for name in names.iter().filter(|name| pattern.matches(name)) {
+                let path = dir.join(name);
+                match fs::metadata(&path) {
+                    Ok(meta) if meta.len() > self.max_size => {
+                        debug!("{} is too big for the cache", path.display());
+                        continue;
+                    }
+                    _ => (),
+                }
+                match fs::read(&path) {
+                    Ok(data) => {
+                        debug!("Found album art file {}", path.display());
+                        return Some(data);
+                    }
+                    Err(e) => debug!("Can't read {}: {e}", path.display()),
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Directories like `CD1` or `Disc 2`
+fn is_disc_dir(dir: &Path) -> bool {
+    let Some(name) = dir.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let name = name.to_ascii_lowercase();
+    let Some(rest) = ["cd", "disc", "disk"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+    else {
+        return false;
+    };
+    let rest = rest.trim_start_matches([' ', '_', '-', '.']);
+    rest.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Picture embedded in the tags of a song, front cover preferred
+fn read_embedded(path: &Path, max_size: u64) -> Result<Option<Vec<u8>>> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some("flac") => read_flac_picture(BufReader::new(File::open(path)?), max_size),
+        Some("mp3" | "aif" | "aiff" | "wav") => read_id3_picture(Tag::read_from_path(path)),
+        Some(ext @ ("ogg" | "oga" | "opus" | "m4a" | "mp4" | "aac")) => {
+            debug!("Reading album art embedded in .{ext} files isn't supported");
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+fn read_id3_picture(tag: id3::Result<Tag>) -> Result<Option<Vec<u8>>> {
+    let tag = match tag {
+        Ok(tag) => tag,
+        Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let picture = tag
+        .pictures()
+        .find(|p| p.picture_type == PictureType::CoverFront)
+        .or_else(|| tag.pictures().next());
+    Ok(picture.map(|p| p.data.clone()))
+}
+
+/// Walk the metadata blocks of a FLAC file looking for pictures of at most
+/// `max_size` bytes
+fn read_flac_picture(mut f: impl Read + Seek, max_size: u64) -> Result<Option<Vec<u8>>> {
+    let mut magic = [0; 4];
+    f.read_exact(&mut magic)?;
+    if &magic[..3] == b"ID3" {
+        // Some taggers put an ID3 tag in front, skip it
+        let mut header = [0; 6];
+        f.read_exact(&mut header)?;
+        let size = header[2..]
+            .iter()
+            .fold(0, |size, &b| (size << 7) | u64::from(b & 0x7f));
+        f.seek(SeekFrom::Start(10 + size))?;
+        f.read_exact(&mut magic)?;
+    }
+    if &magic != b"fLaC" {
+        bail!("not a FLAC file");
+    }
+
+    let mut found = None;
+    loop {
+        let mut header = [0; 4];
+        f.read_exact(&mut header)?;
+        let last = header[0] & 0x80 != 0;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+        // The picture is smaller than its block, which can be 16 MiB
+        if header[0] & 0x7f == FLAC_PICTURE && u64::from(len) <= max_size {
+            let mut block = vec![0; len as usize];
+            f.read_exact(&mut block)?;
+            if let Some((kind, data)) = parse_flac_picture(&block) {
+                if kind == FRONT_COVER {
+                    return Ok(Some(data.to_vec()));
+                }
+                found.get_or_insert_with(|| data.to_vec());
+            }
+        } else {
+            f.seek(SeekFrom::Current(len.into()))?;
+        }
+        if last {
+            return Ok(found);
+        }
+    }
+}
+
+/// Picture type and data of a FLAC picture block
+fn parse_flac_picture(block: &[u8]) -> Option<(u32, &[u8])> {
+    let u32_at = |pos: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(
+            block.get(pos..pos.checked_add(4)?)?.try_into().ok()?,
+        ))
+    };
+    let kind = u32_at(0)?;
+    let mime_len = usize::try_from(u32_at(4)?).ok()?;
+    let desc_pos = mime_len.checked_add(8)?;
+    let desc_len = usize::try_from(u32_at(desc_pos)?).ok()?;
+    // Skip width, height, color depth and number of colors
+    let data_pos = desc_pos.checked_add(desc_len)?.checked_add(4 + 16)?;
+    let data_len = usize::try_from(u32_at(data_pos)?).ok()?;
+    let data = block.get(data_pos + 4..(data_pos + 4).checked_add(data_len)?)?;
+    Some((kind, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// A FLAC picture block of `kind` holding `data`
+    fn picture(kind: u32, data: &[u8]) -> Vec<u8> {
+        let mut block = kind.to_be_bytes().to_vec();
+        block.extend(9u32.to_be_bytes());
+        block.extend(b"image/png");
+        block.extend(0u32.to_be_bytes());
+        block.extend([0; 16]);
+        block.extend((data.len() as u32).to_be_bytes());
+        block.extend(data);
+        block
+    }
+
+    /// A FLAC file made of `(block type, contents)` metadata blocks
+    fn flac(blocks: &[(u8, Vec<u8>)]) -> Cursor<Vec<u8>> {
+        let mut file = b"fLaC".to_vec();
+        for (i, (kind, block)) in blocks.iter().enumerate() {
+            let last = if i == blocks.len() - 1 { 0x80 } else { 0 };
+            file.push(kind | last);
+            file.extend(&(block.len() as u32).to_be_bytes()[1..]);
+            file.extend(block);
+        }
+        Cursor::new(file)
+    }
+
+    #[test]
+    fn flac_pictures() {
+        let streaminfo = (0, vec![0; 34]);
+        let file = flac(&[
+            streaminfo.clone(),
+            (FLAC_PICTURE, picture(4, b"back")),
+            (FLAC_PICTURE, picture(FRONT_COVER, b"front")),
+        ]);
+        assert_eq!(read_flac_picture(file, MIB).unwrap().unwrap(), b"front");
+
+        let file = flac(&[streaminfo.clone(), (FLAC_PICTURE, picture(4, b"back"))]);
+        assert_eq!(read_flac_picture(file, MIB).unwrap().unwrap(), b"back");
+
+        let file = flac(&[streaminfo]);
+        assert_eq!(read_flac_picture(file, MIB).unwrap(), None);
+
+        // With an ID3 tag in front
+        let mut id3 = b"ID3\x04\0\0\0\0\0\x02xx".to_vec();
+        id3.extend(flac(&[(FLAC_PICTURE, picture(FRONT_COVER, b"front"))]).into_inner());
+        let found = read_flac_picture(Cursor::new(id3), MIB).unwrap();
+        assert_eq!(found.unwrap(), b"front");
+
+        assert!(read_flac_picture(Cursor::new(b"OggS....".to_vec()), MIB).is_err());
+    }
+
+    #[test]
+    fn truncated_flac_pictures() {
+        // The file ends in the middle of the block
+        let mut file = flac(&[(FLAC_PICTURE, picture(FRONT_COVER, b"front"))]).into_inner();
+        file.truncate(file.len() - 2);
+        assert!(read_flac_picture(Cursor::new(file), MIB).is_err());
+
+        // The picture claims to be longer than its block
+        let mut block = picture(FRONT_COVER, b"front");
+        block.truncate(block.len() - 2);
+        assert_eq!(parse_flac_picture(&block), None);
+        let file = flac(&[(FLAC_PICTURE, block)]);
+        assert_eq!(read_flac_picture(file, MIB).unwrap(), None);
+
+        for len in [0, 3, 8, 12, 40] {
+            let block = picture(FRONT_COVER, b"front");
+            assert_eq!(parse_flac_picture(&block[..len]), None);
+        }
+    }
+
+    #[test]
+    fn oversized_flac_pictures() {
+        // Lengths that would run past the block or overflow
+        let mut block = picture(FRONT_COVER, b"front");
+        let data_len = block.len() - 5 - 4;
+        block[data_len..data_len + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(parse_flac_picture(&block), None);
+        let mut block = picture(FRONT_COVER, b"front");
+        block[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(parse_flac_picture(&block), None);
+
+        // Pictures bigger than the cache are skipped without reading them
+        let big = picture(FRONT_COVER, &[1; 1000]);
+        let file = flac(&[(FLAC_PICTURE, big.clone())]);
+        assert_eq!(read_flac_picture(file, 100).unwrap(), None);
+        let file = flac(&[(FLAC_PICTURE, big), (FLAC_PICTURE, picture(4, b"small"))]);
+        assert_eq!(read_flac_picture(file, 100).unwrap().unwrap(), b"small");
+    }
+
+    #[test]
+    fn disc_dirs() {
+        for dir in ["CD1", "cd 2", "Disc 1", "disc_03", "DISK-2", "cd.4"] {
+            assert!(is_disc_dir(Path::new(dir)), "{dir}");
+        }
+        for dir in ["CDs", "Discography", "Album", "cd", "1"] {
+            assert!(!is_disc_dir(Path::new(dir)), "{dir}");
+        }
+
+        let music = std::env::temp_dir().join(format!("mpdris2-rs-local-{}", std::process::id()));
+        let album = music.join("Album");
+        fs::create_dir_all(album.join("CD2")).unwrap();
+        fs::create_dir_all(album.join("Extras")).unwrap();
+        fs::write(album.join("CD2/01.flac"), b"not really").unwrap();
+        fs::write(album.join("Extras/01.flac"), b"not really").unwrap();
+        fs::write(album.join("Cover.JPG"), b"cover").unwrap();
+        fs::write(album.join("front.png"), b"front").unwrap();
+        fs::write(album.join("notes.txt"), b"notes").unwrap();
+
+        let names = DEFAULT_COVER_NAMES.map(String::from);
+        let local = LocalArt::new(music.clone(), &names, MIB);
+        assert_eq!(local.find("Album/CD2/01.flac").unwrap(), b"cover");
+        // Only disc directories look one level up
+        assert_eq!(local.find("Album/Extras/01.flac"), None);
+        assert_eq!(local.find("../Album/CD2/01.flac"), None);
+        // Covers that don't fit in the cache are skipped
+        let local = LocalArt::new(music.clone(), &names, 3);
+        assert_eq!(local.find("Album/CD2/01.flac"), None);
+
+        fs::remove_dir_all(&music).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+//! Fetching album art from MPD
+mod cache;
+mod http;
+mod local;
+mod process;
+pub use cache::{AlbumArtCache, Lookup};
+pub use http::ArtServer;
+pub use local::{LocalArt, DEFAULT_COVER_NAMES};
+pub use process::THUMBNAIL_SIZE;
+
+use super::{quote, MpdClient, MpdError, MpdErrorType};
+
+use anyhow::{bail, Result};
+use log::debug;
+use std::{collections::HashMap, path::PathBuf};
+
+/// Where album art is cached unless told otherwise
+pub fn default_album_art_dir() -> PathBuf {
+    match dirs::cache_dir().or_else(dirs::runtime_dir) {
+        Some(path) => path.join("mpdris2-rs/album_art"),
+        // No home directory, the cache checks that nobody else owns this
+        None => std::env::temp_dir().join("mpdris2-rs-album_art"),
+    }
+}
+
+/// Songs sharing a key share their album art: same album in the same
+/// directory. Streams are keyed by their URL.
+pub fn album_key(song: &HashMap<String, Vec<String>>) -> Option<String> {
+    let uri = song.get("file")?.first()?;
+    if uri.contains("://") {
+        return Some(format!("stream\0{uri}"));
+    }
+    let dir = uri.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let album = song
+        .get("Album")
+        .and_then(|a| a.first())
+        .map_or("", String::as_str);
+    Some(format!("file\0{dir}\0{album}"))
+}
+
+/// Download the album art of `uri`: embedded picture first, then the cover
+/// file in its directory. Returns `None` if there's neither.
+pub async fn fetch(c: &mut MpdClient, uri: &str) -> Result<Option<Vec<u8>>> {
+    if let Some(data) = read_binary(c, "readpicture", uri).await? {
+        debug!("Found embedded album art for {uri}");
+        return Ok(Some(data));
+    }
+    if let Some(data) = read_binary(c, "albumart", uri).await? {
+        debug!("Found album art file for {uri}");
+        return Ok(Some(data));
+    }
+    debug!("No album art found for {uri}");
+    Ok(None)
+}
+
+/// Read a whole binary response of `cmd`, chunk by chunk
+async fn read_binary(c: &mut MpdClient, cmd: &str, uri: &str) -> Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    loop {
+        let resp = match c
+            .issue_command(&format!("{cmd} {} {}", quote(uri)?, data.len()))
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) => match e.downcast_ref::<MpdError>().map(MpdError::kind) {
+                Some(MpdErrorType::NoExist) => return Ok(None),
+                _ => return Err(e),
+            },
+        };
+        // `readpicture` answers with nothing at all if there's no picture
+        let size: usize = match resp.fields.iter().find(|(name, _)| name == "size") {
+            Some((_, size)) => size.parse()?,
+            None => return Ok(None),
+        };
+        match resp.binary {
+            Some(chunk) if !chunk.is_empty() => data.extend(chunk),
+            _ => bail!("MPD sent an empty chunk of {uri}"),
+        }
+        if data.len() >= size {
+            return Ok(Some(data));
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Turning whatever MPD sends into images everyone can show
+//!
+//! Without the `image` feature, images aren't decoded at all: PNG and JPEG
+//! are used as they are, thumbnails included, and other formats are refused.
+#[cfg(feature = "image")]
+use anyhow::Context;
+use anyhow::{bail, Result};
+#[cfg(feature = "image")]
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat};
+#[cfg(feature = "image")]
+use std::io::Cursor;
+
+/// Longest side of thumbnails, in pixels
+pub const THUMBNAIL_SIZE: u32 = 256;
+#[cfg(feature = "image")]
+const JPEG_QUALITY: u8 = 85;
+
+/// Album art in a widely supported format
+pub struct Processed {
+    /// File extension matching the format of both variants
+    pub ext: &'static str,
+    /// Full size image
+    pub full: Vec<u8>,
+    /// At most [`THUMBNAIL_SIZE`] pixels wide and high
+    pub thumbnail: Vec<u8>,
+}
+
+/// Sniff the format of `data`, convert it to PNG unless it's already PNG or
+/// JPEG, and make a thumbnail. This is slow, don't run it on the runtime.
+#[cfg(feature = "image")]
+pub fn process(data: Vec<u8>) -> Result<Processed> {
+    let format = image::guess_format(&data).context("unknown image format")?;
+    let img = image::load_from_memory_with_format(&data, format)
+        .with_context(|| format!("can't decode {format:?} image"))?;
+
+    let (format, full) = match format {
+        // Everyone can show these, keep the original bytes
+        ImageFormat::Png | ImageFormat::Jpeg => (format, data),
+        _ => (ImageFormat::Png, encode(&img, ImageFormat::Png)?),
+    };
+
+    let thumbnail = if img.width() > THUMBNAIL_SIZE || img.height() > THUMBNAIL_SIZE {
+        encode(&img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE), format)?
+    } else {
+        full.clone()
+    };
+
+    let ext = match format {
+        ImageFormat::Jpeg => "jpg",
+        _ => "png",
+    };
+    Ok(Processed {
+        ext,
+        full,
+        thumbnail,
+    })
+}
+
+/// Sniff the format of `data` and keep it if it's PNG or JPEG
+#[cfg(not(feature = "image"))]
+pub fn process(data: Vec<u8>) -> Result<Processed> {
+    let ext = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "png"
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        "jpg"
+    } else {
+        bail!("only PNG and JPEG images can be used without the image feature");
+    };
+    Ok(Processed {
+        ext,
+        thumbnail: data.clone(),
+        full: data,
+    })
+}
+
+/// Whether a file called `name` looks like an image we can use
+#[cfg(feature = "image")]
+pub fn is_image_name(name: &str) -> bool {
+    ImageFormat::from_path(name).is_ok()
+}
+
+/// Whether a file called `name` looks like an image we can use
+#[cfg(not(feature = "image"))]
+pub fn is_image_name(name: &str) -> bool {
+    let ext = name.rsplit_once('.').map_or("", |(_, ext)| ext);
+    ["png", "jpg", "jpeg"].contains(&ext.to_ascii_lowercase().as_str())
+}
+
+#[cfg(feature = "image")]
+fn encode(img: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let img = DynamicImage::ImageRgb8(img.to_rgb8());
+            img.write_with_encoder(JpegEncoder::new_with_quality(&mut buf, JPEG_QUALITY))?;
+        }
+        ImageFormat::Png => img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?,
+        _ => bail!("can't encode {format:?}"),
+    }
+    Ok(buf)
+}
@@ -1,65 +1,163 @@
-/// A simple MPD client implementation
-use super::{parse_error_line, parse_line, types::MpdResponse};
+//! A simple MPD client implementation
+use super::{parse_error_line, parse_line, types::MpdResponse, MpdError};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use log::{debug, error, info};
+use std::{future::Future, time::Duration};
 use tokio::{
     io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpStream,
     },
-    time::sleep,
+    select,
+    time::{sleep, timeout},
 };
 
-pub struct MpdClient {
+struct MpdConnection {
     reader: BufReader<OwnedReadHalf>,
     writer: BufWriter<OwnedWriteHalf>,
+}
+
+/// A single connection to MPD. Commands are issued one at a time.
+pub struct MpdClient {
+    // None if the connection is broken
+    conn: Option<MpdConnection>,
 
     // MPD info
     ip: String,
     port: u32,
+    // Sent right after connecting if set
+    password: Option<String>,
+    // Partition to switch to after connecting, the default one if None
+    partition: Option<String>,
+    // Longest connecting or a command may take, no limit if None
+    timeout: Option<Duration>,
 }
 
 impl MpdClient {
-    pub async fn new(ip: &str, port: u32) -> Result<Self> {
-        let stream = TcpStream::connect(format!("{}:{}", ip, port))
+    /// Connect to MPD at `ip:port`
+    pub async fn connect(ip: &str, port: u32) -> Result<Self> {
+        let mut res = MpdClient::disconnected(ip, port);
+        res.reconnect()
             .await
             .context(format!("Cannot connect to MPD server at {ip}:{port}"))?;
-        let (r, w) = stream.into_split();
-        let mut reader = BufReader::new(r);
-        let writer = BufWriter::new(w);
-        // Read version info
-        let mut hello = String::new();
-        reader.read_line(&mut hello).await?;
-
-        Ok(MpdClient {
+        Ok(res)
+    }
+
+    /// Create a client without connecting to MPD yet
+    pub fn disconnected(ip: &str, port: u32) -> Self {
+        MpdClient {
+            conn: None,
             ip: ip.to_owned(),
             port,
-            reader,
-            writer,
-        })
+            password: None,
+            partition: None,
+            timeout: None,
+        }
     }
 
-    async fn reconnect(&mut self) -> Result<()> {
-        let stream = TcpStream::connect(format!("{}:{}", self.ip, self.port))
-            .await
-            .context(format!(
-                "Cannot reconnect to MPD server at {}:{}",
-                self.ip, self.port
-            ))?;
-        let (r, w) = stream.into_split();
-        self.reader = BufReader::new(r);
-        self.writer = BufWriter::new(w);
+    /// Give up on connecting and on commands that take longer than
+    /// `timeout`, and drop the connection as if it broke. Otherwise a
+    /// half-open connection or an unreachable host can hang a command for
+    /// good. Doesn't apply to waiting in `idle`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Log in with `password` from the next (re)connect on
+    pub fn set_password(&mut self, password: Option<String>) {
+        self.password = password;
+    }
+
+    /// Use `partition` instead of the default partition, from the next
+    /// (re)connect on
+    pub fn set_partition(&mut self, partition: Option<String>) {
+        self.partition = partition;
+    }
 
-        let mut hello = String::new();
-        self.reader.read_line(&mut hello).await?;
+    /// The partition this client works on, `None` for the default one
+    pub fn partition(&self) -> Option<&str> {
+        self.partition.as_deref()
+    }
+
+    /// Names of every partition
+    pub async fn list_partitions(&mut self) -> Result<Vec<String>> {
+        let resp = self.issue_command("listpartitions").await?;
+        Ok(resp
+            .fields
+            .into_iter()
+            .filter(|(name, _)| name == "partition")
+            .map(|(_, partition)| partition)
+            .collect())
+    }
 
+    /// Whether the connection is up, as far as we know
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    /// (Re)establish the connection to MPD
+    pub async fn reconnect(&mut self) -> Result<()> {
+        self.conn = None;
+        let connect = async {
+            let stream = TcpStream::connect(format!("{}:{}", self.ip, self.port))
+                .await
+                .context(format!(
+                    "Cannot reconnect to MPD server at {}:{}",
+                    self.ip, self.port
+                ))?;
+            let (r, w) = stream.into_split();
+            let mut reader = BufReader::new(r);
+            let writer = BufWriter::new(w);
+
+            // Read version info
+            let mut hello = String::new();
+            reader.read_line(&mut hello).await?;
+            if !hello.starts_with("OK MPD") {
+                bail!("Unexpected greeting from MPD server: {}", hello.trim_end());
+            }
+            Ok(MpdConnection { reader, writer })
+        };
+        let conn = match self.timeout {
+            Some(limit) => timeout(limit, connect).await.unwrap_or_else(|_| {
+                bail!(
+                    "Timed out connecting to MPD server at {}:{}",
+                    self.ip,
+                    self.port
+                )
+            })?,
+            None => connect.await?,
+        };
+
+        self.conn = Some(conn);
+        if let Some(password) = self.password.clone() {
+            // Keep the password out of logs and errors
+            let res = match quote(&password) {
+                Ok(arg) => self.send(&format!("password {arg}"), "password").await,
+                Err(_) => Err(anyhow!("The password contains a line break")),
+            };
+            if let Err(e) = res {
+                self.conn = None;
+                return Err(e.context("MPD refused the password"));
+            }
+        }
+        if let Some(partition) = self.partition.clone() {
+            let res = match quote(&partition) {
+                Ok(arg) => self.issue_command(&format!("partition {arg}")).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                self.conn = None;
+                return Err(e.context(format!("Cannot switch to MPD partition {partition}")));
+            }
+        }
         Ok(())
     }
 
-    pub async fn reconnect_until_success(&mut self) {
-        error!("MPD connection broken, attempting reconnect...");
+    /// Keep trying to reconnect every `retry_interval` until it works
+    pub async fn reconnect_until_success(&mut self, retry_interval: Duration) {
+        error!("MPD connection unavailable, attempting reconnect...");
         let mut first_retry = true;
         loop {
             match self.reconnect().await {
@@ -70,13 +168,13 @@ impl MpdClient {
                 Err(e) => {
                     if first_retry {
                         error!("Reconnect failed: {}", e);
-                        error!("Will reattempt every 5s...");
+                        error!("Will reattempt every {}s...", retry_interval.as_secs());
                         first_retry = false;
                     } else {
                         debug!("Reconnect failed");
                     }
 
-                    sleep(crate::RETRY_INTERVAL).await;
+                    sleep(retry_interval).await;
                 }
             }
         }
@@ -84,18 +182,108 @@ impl MpdClient {
 
     /// Issue command to MPD server and wait for response.
     /// Returns when response has been received and parsed.
+    ///
+    /// If the connection breaks, the client is marked as disconnected and
+    /// commands will fail immediately until `reconnect` succeeds.
     pub async fn issue_command(&mut self, cmd: &str) -> Result<MpdResponse> {
+        self.send(cmd, cmd).await
+    }
+
+    /// Issue `cmd`, calling it `shown` in logs and errors
+    async fn send(&mut self, cmd: &str, shown: &str) -> Result<MpdResponse> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => bail!("Not connected to MPD server at {}:{}", self.ip, self.port),
+        };
+
+        debug!("Issuing command to MPD: {}", shown);
+        let mut real_cmd = cmd.to_owned();
+        real_cmd.push('\n');
+
+        let round_trip = async {
+            conn.writer.write_all(real_cmd.as_bytes()).await?;
+            conn.writer.flush().await?;
+            read_response(&mut conn.reader).await
+        };
+        let res = match self.timeout {
+            Some(limit) => timeout(limit, round_trip)
+                .await
+                .unwrap_or_else(|_| bail!("MPD didn't answer {shown} in time")),
+            None => round_trip.await,
+        };
+        if res.is_ok() {
+            debug!("Command {} returned", shown);
+        }
+        self.check(res)
+    }
+
+    /// Issue an `idle` command and wait for MPD to report changes.
+    ///
+    /// If `interrupt` completes first, `noidle` is sent: MPD then answers
+    /// right away with whatever changed so far, and the connection stays
+    /// usable without reconnecting.
+    pub async fn idle<F: Future>(&mut self, cmd: &str, interrupt: F) -> Result<MpdResponse> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => bail!("Not connected to MPD server at {}:{}", self.ip, self.port),
+        };
+
         debug!("Issuing command to MPD: {}", cmd);
         let mut real_cmd = cmd.to_owned();
         real_cmd.push('\n');
 
-        self.writer.write_all(real_cmd.as_bytes()).await?;
-        self.writer.flush().await?;
+        let res = async {
+            conn.writer.write_all(real_cmd.as_bytes()).await?;
+            conn.writer.flush().await?;
+            // Unlike reading lines, waiting for data can be cancelled
+            // without losing any
+            let interrupted = select! {
+                res = conn.reader.fill_buf() => {
+                    res?;
+                    false
+                }
+                _ = interrupt => true,
+            };
+            if interrupted {
+                debug!("Interrupting idle");
+                conn.writer.write_all(b"noidle\n").await?;
+                conn.writer.flush().await?;
+            }
+            read_response(&mut conn.reader).await
+        }
+        .await;
+        self.check(res)
+    }
 
-        let resp = read_response(&mut self.reader).await?;
-        debug!("Command {} returned", cmd);
-        Ok(resp)
+    /// Mark the client as disconnected if `res` is an error other than MPD
+    /// refusing a command
+    fn check<T>(&mut self, res: Result<T>) -> Result<T> {
+        if let Err(e) = &res {
+            // MPD errors are fine, the connection is still usable
+            if e.downcast_ref::<MpdError>().is_none() {
+                self.conn = None;
+            }
+        }
+        res
+    }
+}
+
+/// Quote an argument of a MPD command. Line breaks can't be quoted: they
+/// would end the command and start another one.
+pub fn quote(arg: &str) -> Result<String> {
+    if arg.contains(['\n', '\r']) {
+        bail!("MPD command arguments can't contain line breaks: {arg:?}");
+    }
+    let mut res = String::with_capacity(arg.len() + 2);
+    res.push('"');
+    for c in arg.chars() {
+        if c == '"' || c == '\\' {
+            res.push('\\');
+        }
+        res.push(c);
     }
+    res.push('"');
+    Ok(res)
 }
 
 async fn read_response(r: &mut BufReader<OwnedReadHalf>) -> Result<MpdResponse> {
@@ -104,7 +292,9 @@ async fn read_response(r: &mut BufReader<OwnedReadHalf>) -> Result<MpdResponse>
 
     let mut buf = String::new();
     loop {
-        r.read_line(&mut buf).await?;
+        if r.read_line(&mut buf).await? == 0 {
+            bail!("MPD server closed the connection");
+        }
         if buf.starts_with("OK") {
             // Response ends here
             break;
@@ -140,3 +330,18 @@ async fn read_response(r: &mut BufReader<OwnedReadHalf>) -> Result<MpdResponse>
 
     Ok(MpdResponse { fields, binary })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("a b").unwrap(), r#""a b""#);
+        assert_eq!(quote(r#"say "hi" \o/"#).unwrap(), r#""say \"hi\" \\o/""#);
+        assert_eq!(quote("").unwrap(), r#""""#);
+        // A line break would start another command
+        assert!(quote("a.flac\nclear").is_err());
+        assert!(quote("a.flac\rclear").is_err());
+    }
+}
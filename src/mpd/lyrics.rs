@@ -0,0 +1,217 @@
+//! Finding lyrics of songs and reading LRC files
+use super::{
+    quote,
+    types::{LyricLine, Lyrics},
+    MpdClient,
+};
+
+use anyhow::Result;
+use log::debug;
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+use tokio::fs;
+
+/// Comments that may hold lyrics, as returned by `readcomments`
+const LYRICS_COMMENTS: [&str; 4] = ["LYRICS", "UNSYNCEDLYRICS", "UNSYNCED LYRICS", "USLT"];
+
+/// Lyrics of `song`, looking in order at a `.lrc` file next to it, its
+/// `Lyrics` tag, its comments, then a `.txt` file next to it. Files are only
+/// looked at if we know where the music is.
+pub async fn fetch(
+    c: &mut MpdClient,
+    song: &HashMap<String, Vec<String>>,
+    music_dir: Option<&Path>,
+) -> Result<Option<Lyrics>> {
+    let Some(uri) = song.get("file").and_then(|f| f.first()) else {
+        return Ok(None);
+    };
+    let sidecar = music_dir.and_then(|dir| sidecar_path(dir, uri));
+
+    if let Some(text) = read_sidecar(sidecar.as_deref(), "lrc").await {
+        return Ok(Some(parse(&text)));
+    }
+    if let Some(text) = song.get("Lyrics").and_then(|l| l.first()) {
+        return Ok(Some(parse(text)));
+    }
+    if !uri.contains("://") {
+        match c
+            .issue_command(&format!("readcomments {}", quote(uri)?))
+            .await
+        {
+            Ok(resp) => {
+                let text = resp.fields.into_iter().find(|(name, _)| {
+                    LYRICS_COMMENTS.contains(&name.to_ascii_uppercase().as_str())
+                });
+                if let Some((_, text)) = text {
+                    return Ok(Some(parse(&text)));
+                }
+            }
+            // Not every file can have comments
+            Err(e) => debug!("Can't read comments of {uri}: {e}"),
+        }
+    }
+    if let Some(text) = read_sidecar(sidecar.as_deref(), "txt").await {
+        return Ok(Some(parse(&text)));
+    }
+    Ok(None)
+}
+
+/// `uri` in `music_dir`, without its extension
+fn sidecar_path(music_dir: &Path, uri: &str) -> Option<PathBuf> {
+    let relative = Path::new(uri);
+    // Don't let MPD send us anywhere else
+    if uri.contains("://")
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(music_dir.join(relative))
+}
+
+async fn read_sidecar(song: Option<&Path>, ext: &str) -> Option<String> {
+    let path = song?.with_extension(ext);
+    let data = fs::read(&path).await.ok()?;
+    debug!("Found lyrics in {}", path.display());
+    Some(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Read lyrics, synced if they're in LRC format
+pub fn parse(text: &str) -> Lyrics {
+    let mut offset = 0i64;
+    let mut lines = Vec::new();
+    let mut plain = Vec::new();
+    for line in text.lines() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+        while let Some((tag, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+            if let Some(time) = parse_time(tag) {
+                times.push(time);
+            } else if let Some(ms) = tag.strip_prefix("offset:") {
+                offset = ms.trim().parse().unwrap_or(0);
+            } else if !is_id_tag(tag) {
+                break;
+            }
+            rest = after;
+        }
+        if times.is_empty() {
+            if !is_id_line(line) {
+                plain.push(line.trim_end().to_owned());
+            }
+            continue;
+        }
+        let text = rest.trim().to_owned();
+        plain.push(text.clone());
+        for time in times {
+            lines.push(LyricLine {
+                time,
+                text: text.clone(),
+            });
+        }
+    }
+
+    // A positive offset makes lyrics show up sooner
+    for line in &mut lines {
+        let ms = i64::try_from(line.time.as_millis()).unwrap_or(i64::MAX);
+        line.time = Duration::from_millis(ms.saturating_sub(offset).max(0) as u64);
+    }
+    lines.sort_by_key(|line| line.time);
+    Lyrics {
+        text: plain.join("\n").trim().to_owned(),
+        lines,
+    }
+}
+
+/// `mm:ss`, `mm:ss.xx` or `mm:ss.xxx`
+fn parse_time(tag: &str) -> Option<Duration> {
+    let (min, sec) = tag.split_once(':')?;
+    let min: u64 = min.parse().ok()?;
+    let sec: f64 = sec.parse().ok()?;
+    // Out of range times are garbage, not something to panic on
+    let sec = Duration::try_from_secs_f64(sec).ok()?;
+    Duration::from_secs(min.checked_mul(60)?).checked_add(sec)
+}
+
+/// `[ar:Artist]` and friends
+fn is_id_tag(tag: &str) -> bool {
+    tag.split_once(':')
+        .is_some_and(|(name, _)| !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphabetic()))
+}
+
+fn is_id_line(line: &str) -> bool {
+    let line = line.trim();
+    line.strip_prefix('[')
+        .and_then(|l| l.strip_suffix(']'))
+        .is_some_and(is_id_tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times(lyrics: &Lyrics) -> Vec<(u64, &str)> {
+        let lines = lyrics.lines.iter();
+        lines
+            .map(|l| (l.time.as_millis() as u64, l.text.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn plain_text() {
+        let lyrics = parse("First line\nSecond line\n");
+        assert_eq!(lyrics.text, "First line\nSecond line");
+        assert!(lyrics.lines.is_empty());
+    }
+
+    #[test]
+    fn synced_lines() {
+        let lyrics = parse("[ar:Someone]\n[ti:Song]\n[00:01.50]One\n[00:03.250]Two\n[01:00]Three");
+        assert_eq!(
+            times(&lyrics),
+            [(1500, "One"), (3250, "Two"), (60000, "Three")]
+        );
+        // ID tags don't end up in the text
+        assert_eq!(lyrics.text, "One\nTwo\nThree");
+    }
+
+    #[test]
+    fn repeated_lines() {
+        let lyrics = parse("[00:10.00][00:02.00]Chorus\n[00:05.00]Verse");
+        assert_eq!(
+            times(&lyrics),
+            [(2000, "Chorus"), (5000, "Verse"), (10000, "Chorus")]
+        );
+        assert_eq!(lyrics.text, "Chorus\nVerse");
+    }
+
+    #[test]
+    fn offset() {
+        let lyrics = parse("[offset:500]\n[00:00.20]Early\n[00:02.00]Later");
+        assert_eq!(times(&lyrics), [(0, "Early"), (1500, "Later")]);
+        let lyrics = parse("[offset:-500]\n[00:02.00]Later");
+        assert_eq!(times(&lyrics), [(2500, "Later")]);
+    }
+
+    #[test]
+    fn brackets_in_text() {
+        let lyrics = parse("[00:01.00][Chorus] la la");
+        assert_eq!(times(&lyrics), [(1000, "[Chorus] la la")]);
+    }
+
+    #[test]
+    fn out_of_range_times() {
+        assert_eq!(parse_time("00:1e30"), None);
+        assert_eq!(parse_time("00:inf"), None);
+        assert_eq!(parse_time("00:-1"), None);
+        assert_eq!(parse_time("18446744073709551615:00"), None);
+        assert_eq!(parse_time("01:02.5"), Some(Duration::from_millis(62500)));
+
+        let lyrics = parse("[offset:-9223372036854775808]\n[00:1e30]Bad\n[00:01.00]Good");
+        assert_eq!(lyrics.lines.len(), 1);
+        assert_eq!(lyrics.lines[0].text, "Good");
+    }
+}
@@ -1,10 +1,25 @@
+//! Async MPD client and state tracking
 mod parser;
 use parser::{parse_error_line, parse_line};
+pub use parser::{MpdError, MpdErrorType};
 
 pub mod types;
 
 mod client;
-pub use client::MpdClient;
+pub use client::{quote, MpdClient};
+
+mod album_art;
+pub use album_art::{default_album_art_dir, DEFAULT_COVER_NAMES, THUMBNAIL_SIZE};
+
+mod lyrics;
+pub use lyrics::parse as parse_lyrics;
 
 mod stateserver;
-pub use stateserver::MpdStateServer;
+#[cfg(all(test, feature = "dbus"))]
+pub(crate) use stateserver::idle_subsystems;
+pub use stateserver::{MpdStateServer, ServerOptions, SharedAlbumArt};
+
+use std::time::Duration;
+
+/// How long to wait between attempts to reach MPD, by default
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(5);
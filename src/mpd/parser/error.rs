@@ -6,8 +6,9 @@ use nom::{
 };
 use thiserror::Error;
 
+/// An `ACK` response from MPD
 #[derive(Error, Debug)]
-#[error("MPD respond with an error: {msg} (source)")]
+#[error("MPD respond with an error: {msg} ({source})")]
 pub struct MpdError {
     source: MpdErrorType,
     msg: String,
@@ -16,8 +17,26 @@ pub struct MpdError {
     current_command: String,
 }
 
+impl MpdError {
+    /// What kind of error MPD reported
+    pub fn kind(&self) -> &MpdErrorType {
+        &self.source
+    }
+
+    /// Error message from MPD
+    pub fn message(&self) -> &str {
+        &self.msg
+    }
+
+    /// Which command of a command list failed. Those before it were run.
+    pub fn command_list_no(&self) -> usize {
+        self.command_list_no
+    }
+}
+
 /// See https://github.com/MusicPlayerDaemon/MPD/blob/master/src/protocol/Ack.hxx
 #[derive(Error, Debug)]
+#[allow(missing_docs)]
 pub enum MpdErrorType {
     #[error("Unknown error from MPD: {0}")]
     Unknown(usize),
@@ -29,8 +48,22 @@ pub enum MpdErrorType {
     BadPassword,
     #[error("permission denied")]
     Permission,
+    #[error("unknown command")]
+    UnknownCommand,
     #[error("resource doesn't exist")]
     NoExist,
+    #[error("playlist is full")]
+    PlaylistMax,
+    #[error("system error")]
+    System,
+    #[error("failed to load playlist")]
+    PlaylistLoad,
+    #[error("database update already running")]
+    UpdateAlready,
+    #[error("player sync error")]
+    PlayerSync,
+    #[error("resource already exists")]
+    Exist,
 }
 
 impl From<usize> for MpdErrorType {
@@ -40,7 +73,14 @@ impl From<usize> for MpdErrorType {
             2 => MpdErrorType::BadArgument,
             3 => MpdErrorType::BadPassword,
             4 => MpdErrorType::Permission,
+            5 => MpdErrorType::UnknownCommand,
             50 => MpdErrorType::NoExist,
+            51 => MpdErrorType::PlaylistMax,
+            52 => MpdErrorType::System,
+            53 => MpdErrorType::PlaylistLoad,
+            54 => MpdErrorType::UpdateAlready,
+            55 => MpdErrorType::PlayerSync,
+            56 => MpdErrorType::Exist,
             _ => MpdErrorType::Unknown(e),
         }
     }
@@ -80,6 +120,7 @@ fn parse_error_line_helper(i: &str) -> IResult<&str, (&str, &str, &str, &str)> {
     let (i, _) = char('@')(i)?;
     let (i, command_list_no) = digit1(i)?;
     let (i, _) = char(']')(i)?;
+    let (i, _) = space0(i)?;
 
     // Parse current command block
     let (i, _) = char('{')(i)?;
@@ -90,7 +131,7 @@ fn parse_error_line_helper(i: &str) -> IResult<&str, (&str, &str, &str, &str)> {
     let (i, msg) = take_till(|c| c == '\n')(i)?;
     let (_, _) = tag("\n")(i)?;
 
-    Ok(("", (error_id, msg, command_list_no, current_command)))
+    Ok(("", (error_id, command_list_no, current_command, msg.trim())))
 }
 
 fn command(input: &str) -> IResult<&str, &str> {
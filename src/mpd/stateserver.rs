@@ -1,317 +1,1350 @@
-use super::{types, types::MpdState, MpdClient};
-use crate::types::PlayerStateChange;
+use super::{
+    album_art::{
+        self, album_key, default_album_art_dir, AlbumArtCache, ArtServer, LocalArt, Lookup,
+        DEFAULT_COVER_NAMES,
+    },
+    lyrics, quote, types,
+    types::{AlbumArt, Lyrics, MpdPlaybackState, MpdState, MpdStateChanged, SongStickers},
+    MpdClient, MpdError, RETRY_INTERVAL,
+};
+use crate::{supervisor::Supervisor, types::PlayerStateChange};
 
-use anyhow::{bail, format_err, Result};
-use log::{debug, error};
-use std::{mem::discriminant, path::PathBuf, sync::Arc, time::Duration};
+use anyhow::{bail, format_err, Context, Result};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use futures_util::FutureExt;
+use log::{debug, error, info};
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::Future,
+    mem::discriminant,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{
-    fs,
-    fs::File,
-    io::{AsyncWriteExt, BufWriter},
-    spawn,
-    sync::broadcast::{channel, Receiver, Sender},
-    sync::{Mutex, RwLock},
-    task,
-    time::sleep,
+    fs, select, spawn,
+    sync::broadcast::{self, Receiver},
+    sync::{mpsc, oneshot, watch},
+    task::spawn_blocking,
+    time::{interval, sleep, sleep_until, timeout, Instant, MissedTickBehavior},
 };
 
-const IDLE_CMD: &str = "idle stored_playlist playlist player mixer options";
-const PING_INTERVAL: Duration = Duration::from_secs(55);
+/// Subsystems of the playback state and the queue, which are followed no
+/// matter who listens. The rest are only followed once subscribed to.
+const STATE_SUBSYSTEMS: [MpdStateChanged; 4] = [
+    MpdStateChanged::CurrentPlaylist,
+    MpdStateChanged::Player,
+    MpdStateChanged::Mixer,
+    MpdStateChanged::Options,
+];
+/// How long a queued request may take before the caller gives up on it, and
+/// how long the actor waits for MPD to connect or to answer a command
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub struct MpdStateServer {
-    query_client: Arc<Mutex<MpdClient>>,
-    _ping_task: task::JoinHandle<()>,
-    _idle_task: task::JoinHandle<()>,
+/// Tells state servers apart when they pin the album art they show
+static ART_OWNERS: AtomicU64 = AtomicU64::new(0);
+
+/// Tunables of the state server
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    /// How often to ping MPD to keep the query connection alive
+    pub ping_interval: Duration,
+    /// How long to wait between attempts to reach MPD
+    pub retry_interval: Duration,
+    /// Whether to fetch album art of the current song
+    pub album_art: bool,
+    /// Where to cache album art
+    pub album_art_dir: PathBuf,
+    /// How much album art to keep around, in bytes
+    pub album_art_max_size: u64,
+    /// Serve album art on 127.0.0.1 at this port, 0 for any, and hand out
+    /// `http://` URLs instead of `file://` ones
+    pub album_art_http: Option<u16>,
+    /// Where MPD's music is, to look for album art MPD can't find. Asked
+    /// from MPD if not set, which only works over a local socket.
+    pub music_directory: Option<PathBuf>,
+    /// Names of cover files in the music directory, may contain wildcards
+    pub cover_names: Vec<String>,
+    /// Images to use as album art of internet radio, by stream URL
+    pub station_logos: HashMap<String, PathBuf>,
+    /// Whether to look for lyrics of the current song
+    pub lyrics: bool,
+    /// Password to log in to MPD with
+    pub password: Option<String>,
+    /// MPD partition to follow and control, the default one if `None`
+    pub partition: Option<String>,
+    /// Album art cache and HTTP server. Give state servers of several
+    /// partitions clones of the same one, so that they share them. A state
+    /// server keeps the one it started with when reconfigured.
+    pub shared_album_art: SharedAlbumArt,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            ping_interval: Duration::from_secs(55),
+            retry_interval: RETRY_INTERVAL,
+            album_art: true,
+            album_art_dir: default_album_art_dir(),
+            album_art_max_size: 100 * 1024 * 1024,
+            album_art_http: None,
+            music_directory: None,
+            cover_names: DEFAULT_COVER_NAMES.map(String::from).to_vec(),
+            station_logos: HashMap::new(),
+            lyrics: true,
+            password: None,
+            partition: None,
+            shared_album_art: SharedAlbumArt::default(),
+        }
+    }
+}
+
+/// Album art cache and HTTP server, which may be shared by several state
+/// servers so that they don't fight over the cache directory and the port.
+/// Whichever is reconfigured with new album art settings first sets them up
+/// again for everyone.
+#[derive(Clone, Default)]
+pub struct SharedAlbumArt(Arc<SharedArtInner>);
+
+/// Whether album art is enabled, cache directory, its maximum size and HTTP port
+type ArtSettings = (bool, PathBuf, u64, Option<u16>);
+
+#[derive(Default)]
+struct SharedArtInner {
+    // What the cache and the server were set up with
+    settings: tokio::sync::Mutex<Option<ArtSettings>>,
+    cache: ArcSwapOption<AlbumArtCache>,
+    server: ArcSwapOption<ArtServer>,
+}
+
+impl SharedAlbumArt {
+    /// Set up the cache and the server for `options`, unless they already are
+    async fn update(&self, options: &ServerOptions) {
+        let settings = (
+            options.album_art,
+            options.album_art_dir.clone(),
+            options.album_art_max_size,
+            options.album_art_http,
+        );
+        let mut current = self.0.settings.lock().await;
+        if current.as_ref() == Some(&settings) {
+            return;
+        }
+        // Let go of the port before trying to bind it again
+        self.0.server.store(None);
+        let cache = open_album_art_cache(options).await;
+        if let (Some(new), Some(old)) = (&cache, self.cache()) {
+            new.pin_all(&old);
+        }
+        let server = start_art_server(cache.as_deref(), options).await;
+        self.0.cache.store(cache);
+        self.0.server.store(server.map(Arc::new));
+        *current = Some(settings);
+    }
+
+    fn cache(&self) -> Option<Arc<AlbumArtCache>> {
+        self.0.cache.load_full()
+    }
+
+    /// URL of `path` in the cache, if album art is served over HTTP
+    fn url(&self, path: &Path) -> Option<String> {
+        self.0.server.load().as_ref()?.url(path)
+    }
+}
+
+impl std::fmt::Debug for SharedAlbumArt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedAlbumArt").finish_non_exhaustive()
+    }
+}
+
+/// Requests handled by the state server actor
+enum Request {
+    /// Run a command on the query connection
+    Command {
+        cmd: String,
+        reply: oneshot::Sender<Result<types::MpdResponse>>,
+    },
+    /// Refresh everything and broadcast every change
+    Resync {
+        reply: Option<oneshot::Sender<Result<()>>>,
+    },
+    /// The idle connection reported changes in these subsystems
+    Changed(Vec<MpdStateChanged>),
+    /// The idle connection broke
+    ConnectionLost,
+    /// Clean up and stop
+    Shutdown { reply: oneshot::Sender<()> },
+    /// Switch to other settings, reconnecting if the server changed
+    Reconfigure {
+        target: Target,
+        options: Box<ServerOptions>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// The fetcher is done with the album art of a song
+    AlbumArt { song_id: u64, art: Option<AlbumArt> },
+    /// The fetcher is done with the lyrics of a song
+    Lyrics {
+        song_id: u64,
+        lyrics: Option<Lyrics>,
+    },
+}
+
+/// Something for the fetcher to look for
+#[derive(Clone)]
+struct FetchJob {
+    song_id: u64,
+    song: HashMap<String, Vec<String>>,
+    what: Fetch,
+}
+
+#[derive(Clone)]
+enum Fetch {
+    AlbumArt {
+        cache: Arc<AlbumArtCache>,
+        local: Option<LocalArt>,
+        logos: HashMap<String, PathBuf>,
+    },
+    Lyrics {
+        music_dir: Option<PathBuf>,
+    },
+}
+
+/// Where MPD is
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Target {
+    address: String,
+    port: u32,
+    password: Option<String>,
+    partition: Option<String>,
+    retry_interval: Duration,
+}
 
-    mpd_event_tx: Sender<PlayerStateChange>,
+impl Target {
+    fn client(&self) -> MpdClient {
+        let mut c = MpdClient::disconnected(&self.address, self.port);
+        c.set_password(self.password.clone());
+        c.set_partition(self.partition.clone());
+        c
+    }
 
-    // State caches
-    state: Arc<RwLock<types::MpdState>>,
+    /// Client for the query connection. It gives up on MPD in time, so that
+    /// the actor can't get stuck on it.
+    fn query_client(&self) -> MpdClient {
+        let mut c = self.client();
+        c.set_timeout(Some(COMMAND_TIMEOUT));
+        c
+    }
+}
+
+/// Handle to the MPD state server.
+///
+/// The server itself is an actor that owns the query connection. This handle
+/// is cheap to clone: reads come from a lock-free snapshot of the latest state,
+/// while anything that needs to talk to MPD is queued as a request.
+#[derive(Clone)]
+pub struct MpdStateServer {
+    request_tx: mpsc::Sender<Request>,
+    mpd_event_tx: broadcast::Sender<PlayerStateChange>,
+    // Subsystems the idle loop waits on
+    subsystems: Arc<watch::Sender<BTreeSet<MpdStateChanged>>>,
+
+    // Latest known state
+    state: Arc<ArcSwap<MpdState>>,
 }
 
 impl MpdStateServer {
+    /// Set up the state server with default options. This never waits for
+    /// MPD to become reachable: if MPD is down, the state starts as offline
+    /// and is brought up by the idle task once the server comes back.
     pub async fn init(address: &str, port: u32) -> Result<Self> {
+        Self::init_with(address, port, ServerOptions::default()).await
+    }
+
+    /// Same as [`MpdStateServer::init`], with custom options
+    pub async fn init_with(address: &str, port: u32, options: ServerOptions) -> Result<Self> {
+        Self::start(address, port, options, None).await
+    }
+
+    /// Same as [`MpdStateServer::init_with`], but the tasks of the state
+    /// server run under `supervisor`. The idle connection is restarted if it
+    /// fails and is critical, like the state server itself.
+    pub async fn init_supervised(
+        address: &str,
+        port: u32,
+        options: ServerOptions,
+        supervisor: &Supervisor,
+    ) -> Result<Self> {
+        Self::start(address, port, options, Some(supervisor)).await
+    }
+
+    async fn start(
+        address: &str,
+        port: u32,
+        options: ServerOptions,
+        supervisor: Option<&Supervisor>,
+    ) -> Result<Self> {
+        let target = Target {
+            address: address.to_owned(),
+            port,
+            password: options.password.clone(),
+            partition: options.partition.clone(),
+            retry_interval: options.retry_interval,
+        };
         // Set up query client
-        let mut query_client = MpdClient::new(address, port).await?;
+        let mut query_client = target.query_client();
+        let initial_state = match query_client.reconnect().await {
+            Ok(_) => {
+                let status = query_client.issue_command("status").await?;
+                MpdState::from(status.field_map(), None)?
+            }
+            Err(e) => {
+                error!(
+                    "Failed to connect to MPD server: {e}. Will try again every {} secs...",
+                    options.retry_interval.as_secs()
+                );
+                MpdState::offline()
+            }
+        };
+        let state = Arc::new(ArcSwap::from_pointee(initial_state));
+        let (mpd_event_tx, _) = broadcast::channel(50);
+        let (request_tx, request_rx) = mpsc::channel(32);
 
-        let initial_state = query_client.issue_command("status").await?;
-        let mut initial_state = MpdState::from(initial_state.field_map(), None)?;
-        if let Ok(album_art_path) = update_album_art(&mut query_client).await {
-            initial_state.album_art = Some(album_art_path);
-        }
-        let state = Arc::new(RwLock::new(initial_state));
+        let (idle_target_tx, idle_target_rx) = watch::channel(target.clone());
+        let (subsystems, subsystems_rx) = watch::channel(idle_subsystems([]));
+        let (fetch_jobs, fetch_jobs_rx) = watch::channel(Vec::new());
 
-        // Regularly ping to maintain connection
-        let query_client = Arc::new(Mutex::new(query_client));
-        let qc2 = query_client.clone();
-        let _ping_task = spawn(async move {
-            loop {
-                let mut client = qc2.lock().await;
-                if let Err(e) = client.issue_command("ping").await {
-                    error!("ping failed: {}", e);
-                    client.reconnect_until_success().await;
+        // Tell the tasks of servers of other partitions apart
+        let label = match &options.partition {
+            Some(partition) => format!(" ({partition})"),
+            None => String::new(),
+        };
+        options.shared_album_art.update(&options).await;
+        let actor = StateActor {
+            client: query_client,
+            local_art: None,
+            fetch_jobs,
+            awaiting_art: None,
+            awaiting_lyrics: None,
+            art_owner: ART_OWNERS.fetch_add(1, Ordering::Relaxed),
+            state: state.clone(),
+            tx: mpd_event_tx.clone(),
+            target,
+            options,
+            idle_target_tx,
+        };
+        // The actor owns the state, so it can't be started over
+        let mut actor = Some((actor, request_rx));
+        run_task(
+            supervisor,
+            &format!("state server{label}"),
+            true,
+            move || {
+                let actor = actor.take();
+                async move {
+                    let Some((actor, request_rx)) = actor else {
+                        bail!("the state server can't be restarted");
+                    };
+                    actor.run(request_rx).await;
+                    Ok(())
                 }
-                drop(client);
-                sleep(PING_INTERVAL).await;
-            }
-        });
+            },
+        );
 
         // Create a client that receive MPD state change
-        let (mpd_event_tx, _) = channel(50);
-        let mut idle_client = MpdClient::new(address, port).await?;
-        let s2 = state.clone();
-        let tx = mpd_event_tx.clone();
-        let _idle_task = spawn(async move {
-            loop {
-                let res = idle(&mut idle_client, &s2, &tx).await;
-                if let Err(e) = res {
-                    error!("idle failed, attempting reconnect: {e}");
-                    idle_client.reconnect_until_success().await;
-                }
-            }
+        let (target_rx, tx) = (idle_target_rx.clone(), request_tx.clone());
+        run_task(
+            supervisor,
+            &format!("idle connection{label}"),
+            true,
+            move || {
+                let task = idle_loop(target_rx.clone(), subsystems_rx.clone(), tx.clone());
+                task.map(Ok)
+            },
+        );
+        // And another one for album art and lyrics, which may take a while
+        let tx = request_tx.clone();
+        run_task(supervisor, &format!("fetcher{label}"), false, move || {
+            let task = fetch_loop(idle_target_rx.clone(), fetch_jobs_rx.clone(), tx.clone());
+            task.map(Ok)
         });
 
         let res = MpdStateServer {
-            query_client,
-            _ping_task,
-            _idle_task,
-
+            request_tx,
             mpd_event_tx,
+            subsystems: Arc::new(subsystems),
             state,
         };
         Ok(res)
     }
 
+    /// Subscribe to state changes
     pub fn get_mpd_event_rx(&self) -> Receiver<PlayerStateChange> {
         self.mpd_event_tx.subscribe()
     }
 
-    pub fn get_status(&self) -> Arc<RwLock<MpdState>> {
-        self.state.clone()
+    /// Also follow `subsystems`: changes in them are reported as
+    /// [`PlayerStateChange::Mpd`] to everyone listening on
+    /// [`MpdStateServer::get_mpd_event_rx`], and outputs, partitions and
+    /// stickers in the state are only kept up to date once their subsystems
+    /// are followed.
+    pub fn subscribe(&self, subsystems: &[MpdStateChanged]) {
+        self.subsystems.send_if_modified(|current| {
+            let before = current.len();
+            current.extend(subsystems.iter().cloned());
+            current.len() != before
+        });
     }
 
-    pub async fn update_status(&mut self) -> Result<()> {
-        let mut c = self.query_client.lock().await;
-        update_status(&mut c, &self.state, &self.mpd_event_tx).await?;
-        Ok(())
+    /// Get a snapshot of the latest state. Never waits on MPD.
+    pub fn get_status(&self) -> Arc<MpdState> {
+        self.state.load_full()
     }
 
+    /// Issue a command on the query connection. Fails right away if MPD is
+    /// unreachable, and gives up if MPD doesn't respond in time.
     pub async fn issue_command(&self, cmd: &str) -> Result<types::MpdResponse> {
-        let mut client = self.query_client.lock().await;
-        let resp = client.issue_command(cmd).await;
+        let cmd = cmd.to_owned();
+        self.request(|reply| Request::Command { cmd, reply })
+            .await?
+    }
+
+    /// Refresh everything and broadcast every change, so that new
+    /// subscribers start from the current state
+    pub async fn ready(&self) -> Result<()> {
+        self.request(|reply| Request::Resync { reply: Some(reply) })
+            .await?
+    }
+
+    /// Apply new settings without dropping subscribers. If the server
+    /// changed, both connections are moved over to it.
+    pub async fn reconfigure(
+        &self,
+        address: &str,
+        port: u32,
+        options: ServerOptions,
+    ) -> Result<()> {
+        let target = Target {
+            address: address.to_owned(),
+            port,
+            password: options.password.clone(),
+            partition: options.partition.clone(),
+            retry_interval: options.retry_interval,
+        };
+        self.request(|reply| Request::Reconfigure {
+            target,
+            options: Box::new(options),
+            reply,
+        })
+        .await?
+    }
+
+    /// Stop talking to MPD. The state becomes offline (stopped, no song) and
+    /// every later command fails.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.request(|reply| Request::Shutdown { reply }).await
+    }
+
+    async fn request<T>(&self, f: impl FnOnce(oneshot::Sender<T>) -> Request) -> Result<T> {
+        let (reply, rx) = oneshot::channel();
+        let req = f(reply);
+        let res = timeout(COMMAND_TIMEOUT, async {
+            if self.request_tx.send(req).await.is_err() {
+                bail!("MPD state server is gone");
+            }
+            rx.await
+                .map_err(|_| format_err!("MPD state server dropped the request"))
+        })
+        .await;
+        match res {
+            Ok(res) => res,
+            Err(_) => bail!("Timed out waiting for MPD"),
+        }
+    }
+}
+
+/// The actor behind `MpdStateServer`. It's the only one that talks on the
+/// query connection and the only one that writes the state.
+struct StateActor {
+    client: MpdClient,
+    // Where to look when MPD has no album art
+    local_art: Option<LocalArt>,
+    // What the fetcher should look for
+    fetch_jobs: watch::Sender<Vec<FetchJob>>,
+    // Songs whose art and lyrics are being fetched
+    awaiting_art: Option<u64>,
+    awaiting_lyrics: Option<u64>,
+    // Who we are to the album art cache
+    art_owner: u64,
+    state: Arc<ArcSwap<MpdState>>,
+    tx: broadcast::Sender<PlayerStateChange>,
+    target: Target,
+    options: ServerOptions,
+    // Tells the idle loop where MPD is
+    idle_target_tx: watch::Sender<Target>,
+}
+
+impl StateActor {
+    async fn run(mut self, mut rx: mpsc::Receiver<Request>) {
+        // Regularly ping to maintain connection
+        let mut ping = interval(self.options.ping_interval);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ping.tick().await;
+        // When the next line of synced lyrics starts
+        let mut next_line = None;
+
+        loop {
+            select! {
+                req = rx.recv() => match req {
+                    Some(Request::Shutdown { reply }) => {
+                        self.shutdown().await;
+                        reply.send(()).ok();
+                        break;
+                    }
+                    Some(req) => {
+                        // Don't let one bad request take down the whole server
+                        if AssertUnwindSafe(self.handle(req)).catch_unwind().await.is_err() {
+                            error!("MPD state server panicked while handling a request");
+                        }
+                        if ping.period() != self.options.ping_interval {
+                            ping = interval(self.options.ping_interval);
+                            ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                            ping.tick().await;
+                        }
+                    }
+                    // Every handle is gone
+                    None => break,
+                },
+                _ = ping.tick() => {
+                    if self.client.is_connected() {
+                        if let Err(e) = self.command("ping").await {
+                            error!("ping failed: {}", e);
+                        }
+                    } else if let Err(e) = self.resync().await {
+                        // The idle connection reconnects too, whichever is first
+                        debug!("MPD is still unreachable: {e}");
+                    }
+                }
+                _ = sleep_until(next_line.unwrap_or_else(Instant::now)), if next_line.is_some() => (),
+            }
+            next_line = self.sync_lyrics();
+        }
+    }
+
+    async fn handle(&mut self, req: Request) {
+        match req {
+            Request::Command { cmd, reply } => {
+                let res = self.command(&cmd).await;
+                reply.send(res).ok();
+            }
+            Request::Resync { reply } => {
+                let res = self.resync().await;
+                if let Err(e) = &res {
+                    error!("Failed to resync state: {e}");
+                }
+                if let Some(reply) = reply {
+                    reply.send(res).ok();
+                }
+            }
+            Request::Changed(subsystems) if !self.client.is_connected() => {
+                // MPD is there after all, the query connection gave up on it
+                if let Err(e) = self.resync().await {
+                    error!("Failed to resync state: {e}");
+                }
+                for subsystem in subsystems {
+                    send(&self.tx, PlayerStateChange::Mpd(subsystem));
+                }
+            }
+            Request::Changed(subsystems) => {
+                use MpdStateChanged::*;
+                for subsystem in subsystems {
+                    let res = match &subsystem {
+                        CurrentPlaylist => self.update_queue().await,
+                        Player | Mixer | Options => self.update_status(false).await,
+                        Output => self.update_outputs().await,
+                        Sticker => {
+                            self.update_stickers().await;
+                            Ok(())
+                        }
+                        Partition => {
+                            self.update_partitions().await;
+                            Ok(())
+                        }
+                        _ => Ok(()),
+                    };
+                    if let Err(e) = res {
+                        error!(
+                            "Failed to update state after {} changed: {e}",
+                            subsystem.name()
+                        );
+                    }
+                    send(&self.tx, PlayerStateChange::Mpd(subsystem));
+                }
+            }
+            Request::Reconfigure {
+                target,
+                options,
+                reply,
+            } => {
+                let res = self.reconfigure(target, *options).await;
+                reply.send(res).ok();
+            }
+            Request::AlbumArt { song_id, art } => self.album_art_found(song_id, art),
+            Request::Lyrics { song_id, lyrics } => self.lyrics_found(song_id, lyrics),
+            // Handled by the run loop
+            Request::Shutdown { .. } => (),
+            Request::ConnectionLost => {
+                // See if it's just the idle connection, or MPD itself is gone
+                if !self.client.is_connected() || self.client.issue_command("ping").await.is_err() {
+                    self.go_offline().await;
+                }
+            }
+        }
+    }
+
+    /// Issue a command on the query connection. If the connection turns out
+    /// to be broken, one reconnect is attempted before giving up, so callers
+    /// never hang while MPD is unreachable.
+    async fn command(&mut self, cmd: &str) -> Result<types::MpdResponse> {
+        if !self.state.load().online && !self.client.is_connected() {
+            bail!("MPD server is unreachable");
+        }
+
+        let resp = self.client.issue_command(cmd).await;
         match resp {
             Ok(resp) => Ok(resp),
+            Err(e) if e.downcast_ref::<MpdError>().is_some() => Err(e),
             Err(e) => {
                 error!("Error executing command: {e}");
-                client.reconnect_until_success().await;
-                client.issue_command(cmd).await
+                match self.client.reconnect().await {
+                    Ok(_) => {
+                        info!("Reconnect success.");
+                        if let Err(e) = self.resync().await {
+                            error!("Failed to resync state after reconnect: {e}");
+                        }
+                        self.client.issue_command(cmd).await
+                    }
+                    Err(reconnect_err) => {
+                        error!("Reconnect failed: {reconnect_err}");
+                        self.go_offline().await;
+                        Err(e)
+                    }
+                }
             }
         }
     }
 
-    pub async fn ready(&self) -> Result<()> {
-        use PlayerStateChange::*;
-
-        let mut client = self.query_client.lock().await;
-        let tx = &self.mpd_event_tx;
-        update_status(&mut client, &self.state, tx).await?;
-
-        tx.send(Playback)?;
-        tx.send(Loop)?;
-        tx.send(Shuffle)?;
-        tx.send(Volume)?;
-        tx.send(Song)?;
-        tx.send(NextSong)?;
-        tx.send(Tracklist)?;
+    /// Refresh everything from scratch and broadcast every possible change,
+    /// so that listeners don't hold on to anything stale
+    async fn resync(&mut self) -> Result<()> {
+        if !self.client.is_connected() {
+            self.client.reconnect().await?;
+        }
+        self.find_music_directory().await;
+        self.find_supported_formats().await;
+        self.update_status(true).await?;
+        self.update_queue().await?;
+        self.update_outputs().await?;
+        self.update_partitions().await;
+        broadcast_all(&self.tx);
         Ok(())
     }
-}
 
-async fn idle(
-    c: &mut MpdClient,
-    state: &Arc<RwLock<MpdState>>,
-    tx: &Sender<PlayerStateChange>,
-) -> Result<()> {
-    debug!("Entering idle...");
-    let res = c.issue_command(IDLE_CMD).await?;
-    debug!("Idle interrupted");
-
-    for (name, field) in res.fields {
-        if name == "changed" {
-            match field.as_str() {
-                "stored_playlist" => (),
-                "playlist" => {
-                    tx.send(PlayerStateChange::Tracklist)?;
-                }
-                "player" | "mixer" | "options" => update_status(c, state, tx).await?,
-                _ => (),
+    async fn reconfigure(&mut self, target: Target, mut options: ServerOptions) -> Result<()> {
+        options.shared_album_art = self.options.shared_album_art.clone();
+        let server_changed = target.address != self.target.address
+            || target.port != self.target.port
+            || target.password != self.target.password
+            || target.partition != self.target.partition;
+        let album_art_changed = options.album_art != self.options.album_art
+            || options.album_art_dir != self.options.album_art_dir
+            || options.album_art_max_size != self.options.album_art_max_size
+            || options.album_art_http != self.options.album_art_http
+            || options.music_directory != self.options.music_directory
+            || options.cover_names != self.options.cover_names
+            || options.station_logos != self.options.station_logos;
+        let lyrics_changed = options.lyrics != self.options.lyrics;
+        if album_art_changed {
+            options.shared_album_art.update(&options).await;
+        }
+        self.options = options;
+        if target != self.target {
+            self.target = target.clone();
+            self.idle_target_tx.send_replace(target.clone());
+        }
+
+        if server_changed {
+            info!(
+                "Moving over to MPD server at {}:{}",
+                target.address, target.port
+            );
+            self.client = target.query_client();
+            if let Err(e) = self.resync().await {
+                self.go_offline().await;
+                return Err(e);
             }
+        } else if (album_art_changed || lyrics_changed) && self.client.is_connected() {
+            if album_art_changed {
+                self.find_music_directory().await;
+            }
+            self.update_status(true).await?;
         }
+        Ok(())
     }
 
-    Ok(())
-}
+    /// Figure out where MPD's music is, to look for album art MPD can't find
+    /// and to make URLs of songs
+    async fn find_music_directory(&mut self) {
+        let music_dir = match &self.options.music_directory {
+            Some(dir) => Some(dir.clone()),
+            None => match self.client.issue_command("config").await {
+                Ok(resp) => resp
+                    .fields
+                    .into_iter()
+                    .find(|(name, _)| name == "music_directory")
+                    .map(|(_, dir)| PathBuf::from(dir)),
+                Err(e) => {
+                    // MPD only tells local clients
+                    debug!("Can't ask MPD for its music directory: {e}");
+                    None
+                }
+            },
+        };
+        if let Some(dir) = &music_dir {
+            debug!("Looking for album art in {}", dir.display());
+        }
+        self.local_art = music_dir.clone().map(|dir| {
+            LocalArt::new(
+                dir,
+                &self.options.cover_names,
+                self.options.album_art_max_size,
+            )
+        });
+        let mut new = MpdState::clone(&self.state.load());
+        new.music_directory = music_dir;
+        self.state.store(Arc::new(new));
+    }
 
-async fn update_status(
-    c: &mut MpdClient,
-    state: &Arc<RwLock<types::MpdState>>,
-    tx: &Sender<PlayerStateChange>,
-) -> Result<()> {
-    let new_status = c.issue_command("status").await?;
-    let mut new = if new_status.fields.iter().any(|(name, _)| name == "song") {
-        let metadata = c.issue_command("currentsong").await?.field_map();
-        MpdState::from(new_status.field_map(), Some(metadata))?
-    } else {
-        MpdState::from(new_status.field_map(), None)?
-    };
-    let old = state.read().await.clone();
-
-    if new.song.is_some() && new.song != old.song {
-        match update_album_art(c).await {
-            Ok(new_path) => {
-                new.album_art = Some(new_path);
-                if let Some(path) = &old.album_art {
-                    if path.is_file() {
-                        fs::remove_file(path).await?;
-                    }
+    /// What MPD can play, which only changes when MPD restarts
+    async fn find_supported_formats(&mut self) {
+        let values = |resp: Result<types::MpdResponse>, field: &str| -> Vec<String> {
+            let mut values: Vec<String> = match resp {
+                Ok(resp) => resp
+                    .fields
+                    .into_iter()
+                    .filter(|(name, _)| name == field)
+                    .map(|(_, value)| value)
+                    .collect(),
+                Err(e) => {
+                    error!("Can't ask MPD what it can play: {e}");
+                    Vec::new()
                 }
+            };
+            values.sort();
+            values.dedup();
+            values
+        };
+        let handlers = values(self.client.issue_command("urlhandlers").await, "handler");
+        let mime_types = values(self.client.issue_command("decoders").await, "mime_type");
+
+        let mut new = MpdState::clone(&self.state.load());
+        // Handlers look like `http://`
+        new.uri_schemes = Arc::new(
+            handlers
+                .iter()
+                .filter_map(|handler| handler.strip_suffix("://"))
+                .map(str::to_owned)
+                .collect(),
+        );
+        new.mime_types = Arc::new(mime_types);
+        self.state.store(Arc::new(new));
+    }
+
+    /// Leave an offline state behind. The idle loop stops once the actor is
+    /// gone. Album art stays cached for next time.
+    async fn shutdown(&mut self) {
+        info!("Shutting down MPD state server");
+        self.pin_album_art(None);
+        let old = self.state.swap(Arc::new(MpdState::offline()));
+        if old.online {
+            broadcast_all(&self.tx);
+        }
+    }
+
+    /// Replace the state with an empty, stopped one and tell everyone about it
+    async fn go_offline(&mut self) {
+        if !self.state.load().online {
+            return;
+        }
+        error!("MPD server is unreachable, going offline");
+        self.awaiting_art = None;
+        self.awaiting_lyrics = None;
+        self.fetch_jobs.send_replace(Vec::new());
+        self.pin_album_art(None);
+        self.state.store(Arc::new(MpdState::offline()));
+        broadcast_all(&self.tx);
+    }
+
+    /// Album art of the current song if it's cached. Otherwise it's up to
+    /// the fetcher.
+    async fn cached_album_art(&mut self, state: &MpdState) -> Option<AlbumArt> {
+        self.awaiting_art = None;
+        let cache = self.options.shared_album_art.cache()?;
+        let (key, _) = art_key(state.current_song.as_ref()?, &self.options.station_logos)?;
+        match cache.get(&key).await {
+            Ok(Lookup::Hit(art)) => Some(self.with_url(art)),
+            Ok(Lookup::NoArt) => None,
+            Ok(Lookup::Miss) => {
+                self.awaiting_art = state.song.map(|(_, id)| id);
+                None
             }
             Err(e) => {
-                error!("Failed to update album art: {}", e);
+                error!("Failed to look up album art: {e}");
+                None
+            }
+        }
+    }
+
+    /// Tell the fetcher what we'd like: lyrics and art of the current song
+    /// if they're missing, then art of the next song so it's ready when we
+    /// get there
+    fn request_fetches(&self, state: &MpdState) {
+        let mut jobs = Vec::new();
+        let job = |song_id, song: &HashMap<String, Vec<String>>, what| FetchJob {
+            song_id,
+            song: song.clone(),
+            what,
+        };
+        let art = |cache: &Arc<AlbumArtCache>| Fetch::AlbumArt {
+            cache: cache.clone(),
+            local: self.local_art.clone(),
+            logos: self.options.station_logos.clone(),
+        };
+
+        if let (Some(id), Some(song)) = (self.awaiting_lyrics, &state.current_song) {
+            let music_dir = state.music_directory.clone();
+            jobs.push(job(id, song, Fetch::Lyrics { music_dir }));
+        }
+        if let Some(cache) = &self.options.shared_album_art.cache() {
+            if let (Some(id), Some(song)) = (self.awaiting_art, &state.current_song) {
+                jobs.push(job(id, song, art(cache)));
+            }
+            if let Some((pos, id)) = state.next_song {
+                let next = state.queue.get(pos as usize).filter(|song| {
+                    song.get("Id").and_then(|ids| ids.first()) == Some(&id.to_string())
+                });
+                if let Some(song) = next {
+                    jobs.push(job(id, song, art(cache)));
+                }
             }
         }
-    } else if new.song.is_some() {
-        new.album_art = old.album_art.clone();
-    } else if let Some(path) = old.album_art {
-        if path.is_file() {
-            fs::remove_file(path).await?;
+        self.fetch_jobs.send_replace(jobs);
+    }
+
+    /// The fetcher is done with the art of a song. If it's the song we're
+    /// playing, show its art.
+    fn album_art_found(&mut self, song_id: u64, art: Option<AlbumArt>) {
+        if self.awaiting_art != Some(song_id) {
+            // Prefetched, or we've moved on
+            return;
         }
+        self.awaiting_art = None;
+        let Some(art) = art else {
+            return;
+        };
+        let mut new = MpdState::clone(&self.state.load());
+        new.album_art = Some(self.with_url(art));
+        self.pin_album_art(new.album_art.as_ref());
+        self.state.store(Arc::new(new));
+        send(&self.tx, PlayerStateChange::AlbumArt);
     }
 
-    // Write changes before broadcasting, so that receivers will have the latest state
-    *state.write().await = new;
+    /// The fetcher is done with the lyrics of a song
+    fn lyrics_found(&mut self, song_id: u64, lyrics: Option<Lyrics>) {
+        if self.awaiting_lyrics != Some(song_id) {
+            return;
+        }
+        self.awaiting_lyrics = None;
+        let Some(lyrics) = lyrics else {
+            return;
+        };
+        let mut new = MpdState::clone(&self.state.load());
+        new.lyrics = Some(Arc::new(lyrics));
+        self.state.store(Arc::new(new));
+        send(&self.tx, PlayerStateChange::Lyrics);
+    }
+
+    /// Move on to the line of synced lyrics being sung. Returns when the
+    /// next line starts, if we're playing.
+    fn sync_lyrics(&self) -> Option<Instant> {
+        let state = self.state.load();
+        let lines = state.lyrics.as_ref().map_or(&[][..], |l| &l.lines[..]);
+        let position = state.position();
+        let current = lines.iter().rposition(|line| line.time <= position);
+        if current != state.lyric_line {
+            let mut new = MpdState::clone(&state);
+            new.lyric_line = current;
+            self.state.store(Arc::new(new));
+            send(&self.tx, PlayerStateChange::LyricLine);
+        }
+
+        if !matches!(state.playback_state, MpdPlaybackState::Playing(_)) {
+            return None;
+        }
+        let next = lines.get(current.map_or(0, |i| i + 1))?;
+        Some(Instant::now() + (next.time - position))
+    }
+
+    /// Keep the album art we show from being evicted when another partition
+    /// adds to the cache
+    fn pin_album_art(&self, art: Option<&AlbumArt>) {
+        if let Some(cache) = self.options.shared_album_art.cache() {
+            cache.pin(self.art_owner, art);
+        }
+    }
 
-    // Compare && send state changes
-    let new = state.read().await;
-    if discriminant(&new.playback_state) != discriminant(&old.playback_state) {
-        tx.send(PlayerStateChange::Playback)?;
+    fn with_url(&self, mut art: AlbumArt) -> AlbumArt {
+        art.url = self.options.shared_album_art.url(&art.full);
+        art
     }
-    if new.loop_state != old.loop_state {
-        tx.send(PlayerStateChange::Loop)?;
+
+    async fn update_status(&mut self, refresh_album_art: bool) -> Result<()> {
+        let c = &mut self.client;
+        let new_status = c.issue_command("status").await?;
+        let mut new = if new_status.fields.iter().any(|(name, _)| name == "song") {
+            let metadata = c.issue_command("currentsong").await?.field_map();
+            MpdState::from(new_status.field_map(), Some(metadata))?
+        } else {
+            MpdState::from(new_status.field_map(), None)?
+        };
+        let old = self.state.load_full();
+
+        // Don't hold up the new song for its art, that's fetched later if
+        // it's not cached yet
+        let song_changed = refresh_album_art || new.song != old.song;
+        if song_changed {
+            new.album_art = self.cached_album_art(&new).await;
+            self.pin_album_art(new.album_art.as_ref());
+            new.stickers = self.read_stickers(&new).await;
+            self.awaiting_lyrics = new.song.filter(|_| self.options.lyrics).map(|(_, id)| id);
+        } else if new.song.is_some() {
+            new.album_art.clone_from(&old.album_art);
+            new.stickers.clone_from(&old.stickers);
+            new.lyrics.clone_from(&old.lyrics);
+            new.lyric_line = old.lyric_line;
+        }
+
+        new.queue = old.queue.clone();
+        new.music_directory.clone_from(&old.music_directory);
+        new.uri_schemes = old.uri_schemes.clone();
+        new.mime_types = old.mime_types.clone();
+        new.outputs = old.outputs.clone();
+        new.partitions = old.partitions.clone();
+        // MPD before 0.22 doesn't tell
+        if new.partition.is_none() {
+            new.partition.clone_from(&self.target.partition);
+        }
+        if song_changed || new.next_song != old.next_song {
+            self.request_fetches(&new);
+        }
+
+        // Write changes before broadcasting, so that receivers will have the latest state
+        self.state.store(Arc::new(new));
+
+        // Compare && send state changes
+        let new = self.state.load();
+        let tx = &self.tx;
+        if new.online != old.online {
+            send(tx, PlayerStateChange::Connection);
+        }
+        if discriminant(&new.playback_state) != discriminant(&old.playback_state) {
+            send(tx, PlayerStateChange::Playback);
+        }
+        if new.loop_state != old.loop_state {
+            send(tx, PlayerStateChange::Loop);
+        }
+        if new.random != old.random {
+            send(tx, PlayerStateChange::Shuffle);
+        }
+        // Internet radio changes its tags without moving to another song
+        if new.song != old.song || new.current_song != old.current_song {
+            send(tx, PlayerStateChange::Song);
+        }
+        if new.next_song != old.next_song {
+            send(tx, PlayerStateChange::NextSong);
+        }
+        if new.volume != old.volume {
+            send(tx, PlayerStateChange::Volume);
+        }
+
+        Ok(())
     }
-    if new.random != old.random {
-        tx.send(PlayerStateChange::Shuffle)?;
+
+    async fn update_queue(&mut self) -> Result<()> {
+        let songs = self.client.issue_command("playlistinfo").await?.songs();
+        let songs = songs.into_iter().map(types::with_stream_tags).collect();
+        let mut new = MpdState::clone(&self.state.load());
+        new.queue = Arc::new(songs);
+        // The next song may not have been in the queue we knew about
+        self.request_fetches(&new);
+        self.state.store(Arc::new(new));
+        send(&self.tx, PlayerStateChange::Tracklist);
+        Ok(())
     }
-    if new.song != old.song {
-        tx.send(PlayerStateChange::Song)?;
+
+    /// Stickers of the current song. Only songs in the database have them,
+    /// and MPD may have no sticker database at all.
+    async fn read_stickers(&mut self, state: &MpdState) -> SongStickers {
+        let file = state
+            .current_song
+            .as_ref()
+            .and_then(|song| song.get("file"));
+        let Some(file) = file.map(|f| &f[0]).filter(|f| !f.contains("://")) else {
+            return SongStickers::default();
+        };
+        let res = match quote(file) {
+            Ok(arg) => {
+                let cmd = format!("sticker list song {arg}");
+                self.client.issue_command(&cmd).await
+            }
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(resp) => SongStickers::from_response(&resp),
+            Err(e) => {
+                debug!("Can't read stickers of {file}: {e}");
+                SongStickers::default()
+            }
+        }
     }
-    if new.next_song != old.next_song {
-        tx.send(PlayerStateChange::NextSong)?;
+
+    /// A sticker changed, maybe one of the current song
+    async fn update_stickers(&mut self) {
+        let state = self.state.load_full();
+        let stickers = self.read_stickers(&state).await;
+        if stickers == state.stickers {
+            return;
+        }
+        let mut new = MpdState::clone(&state);
+        new.stickers = stickers;
+        self.state.store(Arc::new(new));
+        send(&self.tx, PlayerStateChange::Stickers);
     }
-    if new.volume != old.volume {
-        tx.send(PlayerStateChange::Volume)?;
+
+    /// Partitions came or went. Older MPD has none but the default one.
+    async fn update_partitions(&mut self) {
+        let partitions = match self.client.list_partitions().await {
+            Ok(partitions) => partitions,
+            Err(e) => {
+                debug!("Can't list MPD partitions: {e}");
+                return;
+            }
+        };
+        let mut new = MpdState::clone(&self.state.load());
+        new.partitions = Arc::new(partitions);
+        self.state.store(Arc::new(new));
+        send(&self.tx, PlayerStateChange::Partitions);
     }
 
-    Ok(())
+    async fn update_outputs(&mut self) -> Result<()> {
+        let resp = self.client.issue_command("outputs").await?;
+        let outputs = types::MpdOutput::from_response(&resp)?;
+        let mut new = MpdState::clone(&self.state.load());
+        new.outputs = Arc::new(outputs);
+        self.state.store(Arc::new(new));
+        send(&self.tx, PlayerStateChange::Outputs);
+        Ok(())
+    }
 }
 
-pub async fn update_album_art(c: &mut MpdClient) -> Result<PathBuf> {
-    // Find out song URI
-    let resp = c.issue_command("currentsong").await?;
-    let uri = match resp.field_map().remove("file") {
-        Some(mut uri) => uri.remove(0),
-        None => bail!("invalid MPD response: no current song uri"),
-    };
-    let id = match resp.field_map().remove("Id") {
-        Some(mut id) => id.remove(0),
-        None => bail!("invalid MPD response: no current song ID"),
-    };
-    let pic_dir = match dirs::runtime_dir() {
-        Some(path) => path,
-        None => PathBuf::from("/tmp"),
-    }
-    .join("mpd/album_art/");
-
-    if !pic_dir.is_dir() {
-        fs::create_dir_all(&pic_dir).await?;
-    }
-    let pic_path = pic_dir.join(id);
-    if pic_path.is_file() {
-        fs::remove_file(&pic_path).await?;
-    }
-    let mut pic_file = BufWriter::new(File::create(&pic_path).await?);
-
-    // Try integrated art first
-    let resp = c.issue_command(&format!("readpicture \"{uri}\" 0")).await?;
-    let fields = resp.field_map();
-    let mut offset: u64 = 0;
-    if fields.contains_key("binary") {
-        let size = &fields
-            .get("size")
-            .ok_or_else(|| format_err!("bad mpd response: no size"))?[0];
-        let binary_size = &fields.get("binary").unwrap()[0];
-        pic_file.write_all(&resp.binary.unwrap()).await?;
-        if size != binary_size {
-            offset += binary_size.parse::<u64>()?;
-            loop {
-                // Read the remaining parts
-                let cmd = format!("readpicture \"{uri}\" {offset}");
-                let resp = c.issue_command(&cmd).await?;
-                let size: u64 = fields
-                    .get("size")
-                    .ok_or_else(|| format_err!("bad mpd response: no size"))?[0]
-                    .parse()?;
-                let binary_size: u64 = fields.get("binary").unwrap()[0].parse()?;
-                pic_file.write_all(&resp.binary.unwrap()).await?;
-                if binary_size + offset >= size {
-                    // We've read all of them
+/// Keep an idle connection to MPD and forward whatever changed to the actor.
+/// Moves over to another server whenever `target` changes.
+async fn idle_loop(
+    mut target: watch::Receiver<Target>,
+    mut subsystems: watch::Receiver<BTreeSet<MpdStateChanged>>,
+    request_tx: mpsc::Sender<Request>,
+) {
+    let mut current = target.borrow_and_update().clone();
+    let mut c = current.client();
+    // Subsystems this MPD doesn't know, as older versions have fewer
+    let mut unknown = BTreeSet::new();
+    loop {
+        if subsystems.has_changed().is_err() {
+            // The actor is gone
+            break;
+        }
+        let wanted = subsystems.borrow_and_update().clone();
+        // Other subsystems are wanted while idling: leave idle on the same
+        // connection and go back in with the new ones
+        let interrupt = subsystems.changed();
+        let req = select! {
+            req = idle_once(&mut c, &current, &wanted, &mut unknown, &request_tx, interrupt) => match req {
+                Some(req) => req,
+                None => continue,
+            },
+            changed = target.changed() => {
+                if changed.is_err() {
+                    // The actor is gone
                     break;
                 }
-                offset += binary_size;
+                // Start over, the old connection may be in the middle of a command
+                current = target.borrow_and_update().clone();
+                c = current.client();
+                unknown.clear();
+                continue;
             }
+        };
+        if request_tx.send(req).await.is_err() {
+            break;
         }
-        debug!(
-            "Album art updated from embedded image at {}",
-            pic_path.display()
-        );
-    } else if let Ok(resp) = c.issue_command(&format!("albumart \"{uri}\" 0")).await {
-        // Try cover.jpg instead
-        let fields = resp.field_map();
-        let mut offset: u64 = 0;
-        if fields.contains_key("binary") {
-            let size = &fields
-                .get("size")
-                .ok_or_else(|| format_err!("bad mpd response: no size"))?[0];
-            let binary_size = &fields.get("binary").unwrap()[0];
-            pic_file.write_all(&resp.binary.unwrap()).await?;
-            if size != binary_size {
-                offset += binary_size.parse::<u64>()?;
-                loop {
-                    // Read the remaining parts
-                    let cmd = format!("albumart \"{uri}\" {offset}");
-                    let resp = c.issue_command(&cmd).await?;
-                    let size: u64 = fields
-                        .get("size")
-                        .ok_or_else(|| format_err!("bad mpd response: no size"))?[0]
-                        .parse()?;
-                    let binary_size: u64 = fields.get("binary").unwrap()[0].parse()?;
-                    pic_file.write_all(&resp.binary.unwrap()).await?;
-                    if binary_size + offset >= size {
-                        // We've read all of them
-                        break;
+    }
+}
+
+/// Fetch album art and lyrics on a connection of their own, so that slow
+/// downloads don't hold up anything else. Only the latest list of jobs is
+/// worked on.
+async fn fetch_loop(
+    mut target: watch::Receiver<Target>,
+    mut jobs: watch::Receiver<Vec<FetchJob>>,
+    request_tx: mpsc::Sender<Request>,
+) {
+    let mut current = target.borrow_and_update().clone();
+    let mut c = current.client();
+    while jobs.changed().await.is_ok() {
+        let todo = jobs.borrow_and_update().clone();
+        for job in todo {
+            match target.has_changed() {
+                Ok(true) => {
+                    current = target.borrow_and_update().clone();
+                    c = current.client();
+                }
+                Ok(false) => (),
+                // The actor is gone
+                Err(_) => return,
+            }
+            if jobs.has_changed().unwrap_or(true) {
+                // Something else is wanted now
+                break;
+            }
+            if !c.is_connected() {
+                if let Err(e) = c.reconnect().await {
+                    error!("Can't fetch album art or lyrics: {e}");
+                    // Try again in a while, or go on with whatever is wanted by then
+                    select! {
+                        _ = sleep(current.retry_interval) => (),
+                        changed = jobs.changed() => if changed.is_err() {
+                            return;
+                        },
                     }
-                    offset += binary_size;
+                    jobs.mark_changed();
+                    break;
                 }
-                debug!(
-                    "Album art updated from folder cover file at {}",
-                    pic_path.display()
-                );
             }
-        } else {
-            debug!("No album art found");
+
+            let song_id = job.song_id;
+            let req = match &job.what {
+                Fetch::AlbumArt {
+                    cache,
+                    local,
+                    logos,
+                } => {
+                    let art = find_album_art(&mut c, cache, local.as_ref(), logos, &job.song).await;
+                    let art = art.unwrap_or_else(|e| {
+                        error!("Failed to fetch album art: {e}");
+                        None
+                    });
+                    Request::AlbumArt { song_id, art }
+                }
+                Fetch::Lyrics { music_dir } => {
+                    let lyrics = lyrics::fetch(&mut c, &job.song, music_dir.as_deref()).await;
+                    let lyrics = lyrics.unwrap_or_else(|e| {
+                        error!("Failed to fetch lyrics: {e}");
+                        None
+                    });
+                    Request::Lyrics { song_id, lyrics }
+                }
+            };
+            if request_tx.send(req).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Wait for the next change on the idle connection, reconnecting first if
+/// needed, or until `interrupt` completes. Returns `None` if there's nothing
+/// to report.
+async fn idle_once(
+    c: &mut MpdClient,
+    target: &Target,
+    wanted: &BTreeSet<MpdStateChanged>,
+    unknown: &mut BTreeSet<MpdStateChanged>,
+    request_tx: &mpsc::Sender<Request>,
+    interrupt: impl Future,
+) -> Option<Request> {
+    if !c.is_connected() {
+        c.reconnect_until_success(target.retry_interval).await;
+        // Anything could have happened while we were away
+        request_tx.send(Request::Resync { reply: None }).await.ok();
+    }
+
+    let mut cmd = String::from("idle");
+    for subsystem in wanted.difference(unknown) {
+        cmd.push(' ');
+        cmd.push_str(subsystem.name());
+    }
+    debug!("Entering idle...");
+    match c.idle(&cmd, interrupt).await {
+        Ok(res) => {
+            debug!("Idle interrupted");
+            let changed: Vec<_> = res
+                .fields
+                .iter()
+                .filter(|(name, _)| name == "changed")
+                .map(|(_, field)| MpdStateChanged::from(field.as_str()))
+                .collect();
+            // Nothing changed if we left idle ourselves
+            (!changed.is_empty()).then_some(Request::Changed(changed))
+        }
+        Err(e) => {
+            let rejected = e
+                .downcast_ref::<MpdError>()
+                .and_then(|e| e.message().strip_prefix("Unrecognized idle event: "));
+            if let Some(name) = rejected {
+                info!("MPD doesn't know the {name} subsystem, not following it");
+                unknown.insert(MpdStateChanged::from(name.trim()));
+                return None;
+            }
+            error!("idle failed, attempting reconnect: {e}");
+            if c.is_connected() {
+                // MPD refused the command itself, don't spin on it
+                sleep(target.retry_interval).await;
+                return None;
+            }
+            Some(Request::ConnectionLost)
+        }
+    }
+}
+
+/// What the idle connection waits on when `subscribed` are subscribed to
+pub(crate) fn idle_subsystems(
+    subscribed: impl IntoIterator<Item = MpdStateChanged>,
+) -> BTreeSet<MpdStateChanged> {
+    STATE_SUBSYSTEMS.into_iter().chain(subscribed).collect()
+}
+
+/// Run a task of the state server under `supervisor`, if there's one
+fn run_task<F, Fut>(supervisor: Option<&Supervisor>, name: &str, critical: bool, mut f: F)
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    match supervisor {
+        Some(supervisor) => {
+            supervisor.spawn(name, critical, f);
+        }
+        None => {
+            spawn(f().map(|res| {
+                if let Err(e) = res {
+                    error!("{e}");
+                }
+            }));
+        }
+    }
+}
+
+fn broadcast_all(tx: &broadcast::Sender<PlayerStateChange>) {
+    use PlayerStateChange::*;
+
+    for change in [
+        Connection, Playback, Loop, Shuffle, Volume, Song, NextSong, Tracklist, Outputs, Partitions,
+    ] {
+        send(tx, change);
+    }
+}
+
+/// Broadcast a change. It's fine if nobody is listening yet.
+fn send(tx: &broadcast::Sender<PlayerStateChange>, change: PlayerStateChange) {
+    tx.send(change).ok();
+}
+
+async fn open_album_art_cache(options: &ServerOptions) -> Option<Arc<AlbumArtCache>> {
+    if !options.album_art {
+        return None;
+    }
+    match AlbumArtCache::open(&options.album_art_dir, options.album_art_max_size).await {
+        Ok(cache) => Some(Arc::new(cache)),
+        Err(e) => {
+            error!("Album art disabled: {e:#}");
+            None
         }
     }
-    pic_file.flush().await?;
-    debug!("Album art update finished");
-    Ok(pic_path)
+}
+
+async fn start_art_server(
+    cache: Option<&AlbumArtCache>,
+    options: &ServerOptions,
+) -> Option<ArtServer> {
+    let (Some(cache), Some(port)) = (cache, options.album_art_http) else {
+        return None;
+    };
+    match ArtServer::start(cache.objects(), port).await {
+        Ok(server) => Some(server),
+        Err(e) => {
+            error!("Falling back to file URLs for album art: {e:#}");
+            None
+        }
+    }
+}
+
+/// Cache key of the album art of `song`, and the station logo to use if it's
+/// internet radio that has one. Logos are keyed by their path, so that
+/// pointing a station at another image doesn't show the old one.
+fn art_key<'a>(
+    song: &HashMap<String, Vec<String>>,
+    logos: &'a HashMap<String, PathBuf>,
+) -> Option<(String, Option<&'a Path>)> {
+    let uri = song.get("file")?.first()?;
+    match logos.get(uri) {
+        Some(logo) => Some((format!("logo\0{}", logo.display()), Some(logo))),
+        None => Some((album_key(song)?, None)),
+    }
+}
+
+/// Album art of `song`, from the cache if possible
+async fn find_album_art(
+    c: &mut MpdClient,
+    cache: &AlbumArtCache,
+    local: Option<&LocalArt>,
+    logos: &HashMap<String, PathBuf>,
+    song: &HashMap<String, Vec<String>>,
+) -> Result<Option<AlbumArt>> {
+    let (Some((key, logo)), Some(uri)) = (
+        art_key(song, logos),
+        song.get("file").and_then(|f| f.first()),
+    ) else {
+        return Ok(None);
+    };
+    match cache.get(&key).await? {
+        Lookup::Hit(path) => return Ok(Some(path)),
+        Lookup::NoArt => return Ok(None),
+        Lookup::Miss => (),
+    }
+    if let Some(logo) = logo {
+        // Not cached as missing if it can't be read, it may be fixed soon
+        let data = fs::read(logo)
+            .await
+            .with_context(|| format!("Can't read station logo {}", logo.display()))?;
+        return cache.insert(&key, Some(data)).await;
+    }
+    let mut data = album_art::fetch(c, uri).await?;
+    if let (None, Some(local)) = (&data, local) {
+        let (local, uri) = (local.clone(), uri.clone());
+        data = spawn_blocking(move || local.find(&uri)).await?;
+    }
+    cache.insert(&key, data).await
 }
@@ -1,66 +1,337 @@
+//! Typed representations of MPD responses and state
 use anyhow::{bail, Result};
 use std::path::PathBuf;
-use std::{collections::HashMap, fmt::Display, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-// A list of fields + optional binary data
+/// A list of fields + optional binary data
 #[derive(Debug)]
 pub struct MpdResponse {
+    /// `name: value` pairs, in the order MPD sent them
     pub fields: Vec<(String, String)>,
+    /// Binary chunk, for commands like `albumart`
     pub binary: Option<Vec<u8>>,
 }
 
 impl MpdResponse {
+    /// Group fields by name
     pub fn field_map(&self) -> HashMap<String, Vec<String>> {
-        let mut res = HashMap::new();
+        let mut res: HashMap<String, Vec<String>> = HashMap::new();
         for (name, value) in &self.fields {
-            res.entry(name.clone())
-                .or_insert_with(|| vec![value.clone()])
-                .push(value.to_owned());
+            res.entry(name.clone()).or_default().push(value.to_owned());
+        }
+        res
+    }
+
+    /// Split a song list (like the response of `playlistinfo`) into field maps,
+    /// one for each song
+    pub fn songs(&self) -> Vec<HashMap<String, Vec<String>>> {
+        let mut res = Vec::new();
+        let mut buf: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, value) in &self.fields {
+            // Every song starts with its file
+            if name == "file" && !buf.is_empty() {
+                res.push(std::mem::take(&mut buf));
+            }
+            buf.entry(name.clone()).or_default().push(value.to_owned());
+        }
+        if !buf.is_empty() {
+            res.push(buf);
         }
         res
     }
 }
 
-#[derive(Debug)]
+/// MPD subsystems reported by the `idle` command
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum MpdStateChanged {
+    /// The song database was changed by an update
+    Database,
+    /// A database update started or finished
+    Update,
+    /// A stored playlist was changed, added or removed
     StoredPlaylist,
+    /// The queue
     CurrentPlaylist,
+    /// Playback started, stopped or seeked, or the song changed
     Player,
+    /// Volume
     Mixer,
+    /// An audio output was enabled, disabled or changed
+    Output,
+    /// Repeat, random, crossfade, replay gain and such
     Options,
+    /// A partition was added, removed or changed
+    Partition,
+    /// A sticker was changed
+    Sticker,
+    /// A client subscribed to or left a channel
+    Subscription,
+    /// A message came in on a channel we're subscribed to
+    Message,
+    /// A neighbor was found or lost
+    Neighbor,
+    /// A storage was mounted or unmounted
+    Mount,
+    /// Subsystems of newer MPD
     Unknown(String),
 }
 
+impl MpdStateChanged {
+    /// Name of the subsystem, as `idle` takes and reports it
+    pub fn name(&self) -> &str {
+        use MpdStateChanged::*;
+        match self {
+            Database => "database",
+            Update => "update",
+            StoredPlaylist => "stored_playlist",
+            CurrentPlaylist => "playlist",
+            Player => "player",
+            Mixer => "mixer",
+            Output => "output",
+            Options => "options",
+            Partition => "partition",
+            Sticker => "sticker",
+            Subscription => "subscription",
+            Message => "message",
+            Neighbor => "neighbor",
+            Mount => "mount",
+            Unknown(name) => name,
+        }
+    }
+}
+
 impl From<&str> for MpdStateChanged {
     fn from(i: &str) -> Self {
         use MpdStateChanged::*;
         match i {
+            "database" => Database,
+            "update" => Update,
             "stored_playlist" => StoredPlaylist,
             "playlist" => CurrentPlaylist,
             "player" => Player,
             "mixer" => Mixer,
+            "output" => Output,
             "options" => Options,
+            "partition" => Partition,
+            "sticker" => Sticker,
+            "subscription" => Subscription,
+            "message" => Message,
+            "neighbor" => Neighbor,
+            "mount" => Mount,
             _ => Unknown(i.to_owned()),
         }
     }
 }
 
+/// Everything we know about MPD at some point in time
 #[derive(Debug, Clone)]
 pub struct MpdState {
+    /// Playing, paused or stopped
     pub playback_state: MpdPlaybackState,
+    /// Repeat/single mode
     pub loop_state: MpdLoopState,
+    /// Random mode
     pub random: bool,
+    /// Mixer volume in percent, if MPD has a mixer
     pub volume: Option<u8>,
-    // Option<(playlist_id, song_id)>
+    /// Current song as `(playlist_pos, song_id)`
     pub song: Option<(u64, u64)>,
+    /// Next song as `(playlist_pos, song_id)`
     pub next_song: Option<(u64, u64)>,
+    /// Length of the queue
     pub playlistlength: u64,
 
+    /// Tags of the current song, as returned by `currentsong`
     pub current_song: Option<HashMap<String, Vec<String>>>,
-    pub album_art: Option<PathBuf>,
+    /// Album art of the current song
+    pub album_art: Option<AlbumArt>,
+    /// Rating and play count of the current song
+    pub stickers: SongStickers,
+    /// Lyrics of the current song
+    pub lyrics: Option<Arc<Lyrics>>,
+    /// Index of the current line in `lyrics.lines`, if they're synced
+    pub lyric_line: Option<usize>,
+    /// Songs in the current queue
+    pub queue: Arc<Vec<HashMap<String, Vec<String>>>>,
+    /// Where MPD's music is, if we know
+    pub music_directory: Option<PathBuf>,
+    /// URL schemes MPD can play, like `http`
+    pub uri_schemes: Arc<Vec<String>>,
+    /// MIME types MPD can decode
+    pub mime_types: Arc<Vec<String>>,
+    /// Audio outputs of MPD
+    pub outputs: Arc<Vec<MpdOutput>>,
+    /// Partition we're on, if MPD says
+    pub partition: Option<String>,
+    /// Names of every MPD partition
+    pub partitions: Arc<Vec<String>>,
+
+    /// Whether MPD is reachable at all
+    pub online: bool,
+    /// When this state was read from MPD
+    pub updated_at: Instant,
+}
+
+/// Album art of a song, saved on disk as PNG or JPEG
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AlbumArt {
+    /// Full size image
+    pub full: PathBuf,
+    /// Small version for notifications, see [`crate::mpd::THUMBNAIL_SIZE`]
+    pub thumbnail: PathBuf,
+    /// Where to get the full size image if not from the file, when album
+    /// art is served over HTTP
+    pub url: Option<String>,
+}
+
+/// Stickers other clients keep for a song, as returned by `sticker list`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SongStickers {
+    /// `rating`, from 0 to 10 like myMPD and Cantata write it
+    pub rating: Option<u8>,
+    /// `playcount`
+    pub play_count: Option<u32>,
+    /// `lastplayed`, as a Unix timestamp
+    pub last_played: Option<u64>,
+}
+
+impl SongStickers {
+    /// Read the stickers we know from the response of `sticker list`
+    pub fn from_response(resp: &MpdResponse) -> Self {
+        let mut stickers = SongStickers::default();
+        for (name, value) in &resp.fields {
+            if name != "sticker" {
+                continue;
+            }
+            let Some((key, value)) = value.split_once('=') else {
+                continue;
+            };
+            // Some clients write `playCount` and `lastPlayed`
+            match key.to_ascii_lowercase().as_str() {
+                "rating" => stickers.rating = value.trim().parse().ok().map(|r: u8| r.min(10)),
+                "playcount" => stickers.play_count = value.trim().parse().ok(),
+                "lastplayed" => stickers.last_played = value.trim().parse().ok(),
+                _ => (),
+            }
+        }
+        stickers
+    }
+}
+
+/// An audio output, as returned by `outputs`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MpdOutput {
+    /// Id used by `enableoutput` and friends
+    pub id: u32,
+    /// Name from MPD's configuration
+    pub name: String,
+    /// Output plugin, like `pulse` or `httpd`
+    pub plugin: String,
+    /// Whether MPD plays on it
+    pub enabled: bool,
+    /// Runtime settings of the plugin, changed with `outputset`
+    pub attributes: Vec<(String, String)>,
+}
+
+impl MpdOutput {
+    /// Read every output from the response of `outputs`
+    pub fn from_response(resp: &MpdResponse) -> Result<Vec<Self>> {
+        let mut outputs: Vec<MpdOutput> = Vec::new();
+        for (name, value) in &resp.fields {
+            // Every output starts with its id
+            if name == "outputid" {
+                outputs.push(MpdOutput {
+                    id: value.parse()?,
+                    ..Default::default()
+                });
+                continue;
+            }
+            let Some(output) = outputs.last_mut() else {
+                bail!("MPD sent {name} before outputid");
+            };
+            match name.as_str() {
+                "outputname" => output.name.clone_from(value),
+                "plugin" => output.plugin.clone_from(value),
+                "outputenabled" => output.enabled = mpd_num_to_bool(value, name)?,
+                "attribute" => {
+                    let (k, v) = value.split_once('=').unwrap_or((value, ""));
+                    output.attributes.push((k.to_owned(), v.to_owned()));
+                }
+                _ => (),
+            }
+        }
+        Ok(outputs)
+    }
+}
+
+/// Lyrics of a song
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Lyrics {
+    /// The whole text, without timestamps
+    pub text: String,
+    /// Timed lines, sorted by time. Empty if the lyrics aren't synced.
+    pub lines: Vec<LyricLine>,
+}
+
+/// A line of synced lyrics
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LyricLine {
+    /// When the line starts, from the start of the song
+    pub time: Duration,
+    /// The line itself
+    pub text: String,
 }
 
 impl MpdState {
+    /// State to present while MPD is unreachable
+    pub fn offline() -> Self {
+        MpdState {
+            playback_state: MpdPlaybackState::Stopped,
+            loop_state: MpdLoopState::None,
+            random: false,
+            volume: None,
+            song: None,
+            next_song: None,
+            playlistlength: 0,
+            current_song: None,
+            album_art: None,
+            stickers: SongStickers::default(),
+            lyrics: None,
+            lyric_line: None,
+            queue: Arc::default(),
+            music_directory: None,
+            uri_schemes: Arc::default(),
+            mime_types: Arc::default(),
+            outputs: Arc::default(),
+            partition: None,
+            partitions: Arc::default(),
+            online: false,
+            updated_at: Instant::now(),
+        }
+    }
+
+    /// Current playback position, extrapolated from the last time we asked MPD
+    pub fn position(&self) -> Duration {
+        match &self.playback_state {
+            MpdPlaybackState::Playing(s) => {
+                let elapsed = s.elapsed.unwrap_or_default() + self.updated_at.elapsed();
+                match s.duration {
+                    Some(duration) => elapsed.min(duration),
+                    None => elapsed,
+                }
+            }
+            MpdPlaybackState::Paused(s) => s.elapsed.unwrap_or_default(),
+            MpdPlaybackState::Stopped => Duration::ZERO,
+        }
+    }
+
+    /// Build state from the response of `status`, and `currentsong` if there is one
     pub fn from(
         mut status: HashMap<String, Vec<String>>,
         metadata: Option<HashMap<String, Vec<String>>>,
@@ -92,12 +363,12 @@ impl MpdState {
             let elapsed = status.remove("elapsed");
             let duration = status.remove("duration");
             let elapsed = if let Some(time) = elapsed {
-                Some(Duration::from_secs_f64(time[0].parse()?))
+                Some(Duration::try_from_secs_f64(time[0].parse()?)?)
             } else {
                 None
             };
             let duration = if let Some(time) = duration {
-                Some(Duration::from_secs_f64(time[0].parse()?))
+                Some(Duration::try_from_secs_f64(time[0].parse()?)?)
             } else {
                 None
             };
@@ -118,17 +389,14 @@ impl MpdState {
             MpdPlaybackState::Stopped
         };
 
-        let song = if song.is_some() && song_id.is_some() {
-            Some((song.unwrap()[0].parse()?, song_id.unwrap()[0].parse()?))
+        let song = if let (Some(song), Some(song_id)) = (song, song_id) {
+            Some((song[0].parse()?, song_id[0].parse()?))
         } else {
             None
         };
 
-        let next_song = if next_song.is_some() && next_song_id.is_some() {
-            Some((
-                next_song.unwrap()[0].parse()?,
-                next_song_id.unwrap()[0].parse()?,
-            ))
+        let next_song = if let (Some(next_song), Some(next_song_id)) = (next_song, next_song_id) {
+            Some((next_song[0].parse()?, next_song_id[0].parse()?))
         } else {
             None
         };
@@ -141,15 +409,60 @@ impl MpdState {
             song,
             next_song,
             playlistlength: playlistlength.and_then(|s| s[0].parse().ok()).unwrap_or(0),
-            current_song: metadata,
+            current_song: metadata.map(with_stream_tags),
             album_art: None,
+            stickers: SongStickers::default(),
+            lyrics: None,
+            lyric_line: None,
+            queue: Arc::default(),
+            music_directory: None,
+            uri_schemes: Arc::default(),
+            mime_types: Arc::default(),
+            outputs: Arc::default(),
+            partition: status.remove("partition").map(|mut p| p.swap_remove(0)),
+            partitions: Arc::default(),
+            online: true,
+            updated_at: Instant::now(),
         };
 
         Ok(res)
     }
 }
 
+/// Tags of internet radio the way they are for files: the ICY title
+/// `Artist - Title` is split in two, and the station name is the album.
+/// The original `Name` is kept.
+pub fn with_stream_tags(mut song: HashMap<String, Vec<String>>) -> HashMap<String, Vec<String>> {
+    if !song
+        .get("file")
+        .and_then(|f| f.first())
+        .is_some_and(|uri| uri.contains("://"))
+    {
+        return song;
+    }
+    if !song.contains_key("Artist") {
+        let split = song
+            .get("Title")
+            .and_then(|t| t.first())
+            .and_then(|title| title.split_once(" - "))
+            .map(|(artist, title)| (artist.trim().to_owned(), title.trim().to_owned()))
+            .filter(|(artist, title)| !artist.is_empty() && !title.is_empty());
+        if let Some((artist, title)) = split {
+            song.insert("Artist".to_owned(), vec![artist]);
+            song.insert("Title".to_owned(), vec![title]);
+        }
+    }
+    if !song.contains_key("Album") {
+        if let Some(name) = song.get("Name").cloned() {
+            song.insert("Album".to_owned(), name);
+        }
+    }
+    song
+}
+
+/// Playback status
 #[derive(PartialEq, Eq, Debug, Clone)]
+#[allow(missing_docs)]
 pub enum MpdPlaybackState {
     Playing(MpdPlayingState),
     Paused(MpdPlayingState),
@@ -168,20 +481,28 @@ impl Display for MpdPlaybackState {
     }
 }
 
+/// Position in the current song
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct MpdPlayingState {
+    /// Time elapsed, when the state was read
     pub elapsed: Option<Duration>,
+    /// Length of the song
     pub duration: Option<Duration>,
 }
 
+/// Loop mode, in MPRIS terms
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum MpdLoopState {
+    /// Stop at the end of the queue
     None,
+    /// Repeat the current song (`repeat 1`, `single 1`)
     Track,
+    /// Repeat the whole queue (`repeat 1`, `single 0`)
     Playlist,
 }
 
 impl MpdLoopState {
+    /// Build from the `repeat` and `single` fields of MPD status
     pub fn from_mpd(repeat: &str, single: &str) -> Result<Self> {
         use MpdLoopState::*;
         let repeat = mpd_num_to_bool(repeat, "repeat")?;
@@ -195,15 +516,20 @@ impl MpdLoopState {
         };
         Ok(res)
     }
+}
+
+impl FromStr for MpdLoopState {
+    type Err = anyhow::Error;
 
-    pub fn from_str(s: &str) -> Self {
+    fn from_str(s: &str) -> Result<Self> {
         use MpdLoopState::*;
-        match s {
+        let res = match s {
             "None" => None,
             "Track" => Track,
             "Playlist" => Playlist,
-            _ => None,
-        }
+            _ => bail!("invalid loop status {s}, expect None, Track or Playlist"),
+        };
+        Ok(res)
     }
 }
 
@@ -227,3 +553,163 @@ fn mpd_num_to_bool(i: &str, field_name: &str) -> Result<bool> {
     };
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(fields: &[(&str, &str)]) -> MpdResponse {
+        MpdResponse {
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            binary: None,
+        }
+    }
+
+    fn song(tags: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        let mut song: HashMap<String, Vec<String>> = HashMap::new();
+        for (tag, value) in tags {
+            song.entry(tag.to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+        song
+    }
+
+    #[test]
+    fn stream_tags() {
+        let stream = |title: &str| {
+            with_stream_tags(song(&[
+                ("file", "http://radio/stream"),
+                ("Name", "Radio"),
+                ("Title", title),
+            ]))
+        };
+        let tags = stream("Artist - Song");
+        assert_eq!(tags["Artist"], ["Artist"]);
+        assert_eq!(tags["Title"], ["Song"]);
+        assert_eq!(tags["Album"], ["Radio"]);
+        assert_eq!(tags["Name"], ["Radio"]);
+
+        // Only the first separator splits
+        let tags = stream("A - B - C");
+        assert_eq!(tags["Artist"], ["A"]);
+        assert_eq!(tags["Title"], ["B - C"]);
+
+        // No separator, or nothing on one side of it
+        for title in ["Just a title", "Jingle-Time", " - Song", "Artist - ", " - "] {
+            let tags = stream(title);
+            assert!(!tags.contains_key("Artist"), "{title}");
+            assert_eq!(tags["Title"], [title]);
+        }
+
+        // Streams with real tags keep them
+        let tags = with_stream_tags(song(&[
+            ("file", "http://host/song.ogg"),
+            ("Artist", "Band"),
+            ("Title", "Live - 2001"),
+            ("Album", "Album"),
+            ("Name", "Radio"),
+        ]));
+        assert_eq!(tags["Artist"], ["Band"]);
+        assert_eq!(tags["Title"], ["Live - 2001"]);
+        assert_eq!(tags["Album"], ["Album"]);
+
+        // Files are left alone
+        let file = song(&[("file", "a/b.flac"), ("Title", "Artist - Song")]);
+        assert_eq!(with_stream_tags(file.clone()), file);
+    }
+
+    #[test]
+    fn stickers() {
+        let resp = response(&[
+            ("sticker", "rating=8"),
+            ("sticker", "playCount=12"),
+            ("sticker", "lastPlayed=1700000000"),
+            ("sticker", "other=x"),
+        ]);
+        assert_eq!(
+            SongStickers::from_response(&resp),
+            SongStickers {
+                rating: Some(8),
+                play_count: Some(12),
+                last_played: Some(1_700_000_000),
+            }
+        );
+        assert_eq!(
+            SongStickers::from_response(&response(&[])),
+            SongStickers::default()
+        );
+
+        let rating = |value: &str| {
+            let sticker = format!("rating={value}");
+            SongStickers::from_response(&response(&[("sticker", &sticker)])).rating
+        };
+        assert_eq!(rating("0"), Some(0));
+        assert_eq!(rating(" 10 "), Some(10));
+        // Out of range ratings are capped, or dropped if they aren't one at all
+        assert_eq!(rating("12"), Some(10));
+        assert_eq!(rating("255"), Some(10));
+        for value in ["256", "-1", "7.5", "five", ""] {
+            assert_eq!(rating(value), None, "{value}");
+        }
+
+        // Broken or unrelated lines are skipped
+        let resp = response(&[
+            ("sticker", "playcount=many"),
+            ("sticker", "lastplayed=-5"),
+            ("sticker", "rating"),
+            ("rating", "5"),
+        ]);
+        assert_eq!(SongStickers::from_response(&resp), SongStickers::default());
+    }
+
+    #[test]
+    fn outputs() {
+        let resp = response(&[
+            ("outputid", "0"),
+            ("outputname", "Speakers"),
+            ("plugin", "pulse"),
+            ("outputenabled", "1"),
+            ("outputid", "1"),
+            ("outputname", "Stream"),
+            ("plugin", "httpd"),
+            ("outputenabled", "0"),
+            ("attribute", "dop=0"),
+            ("attribute", "allowed_formats="),
+            ("attribute", "odd"),
+        ]);
+        let outputs = MpdOutput::from_response(&resp).unwrap();
+        assert_eq!(
+            outputs,
+            [
+                MpdOutput {
+                    id: 0,
+                    name: "Speakers".to_owned(),
+                    plugin: "pulse".to_owned(),
+                    enabled: true,
+                    attributes: Vec::new(),
+                },
+                MpdOutput {
+                    id: 1,
+                    name: "Stream".to_owned(),
+                    plugin: "httpd".to_owned(),
+                    enabled: false,
+                    attributes: vec![
+                        ("dop".to_owned(), "0".to_owned()),
+                        ("allowed_formats".to_owned(), String::new()),
+                        ("odd".to_owned(), String::new()),
+                    ],
+                },
+            ]
+        );
+
+        assert!(MpdOutput::from_response(&response(&[])).unwrap().is_empty());
+        assert!(MpdOutput::from_response(&response(&[("outputname", "x")])).is_err());
+        assert!(MpdOutput::from_response(&response(&[("outputid", "x")])).is_err());
+        let enabled = [("outputid", "0"), ("outputenabled", "yes")];
+        assert!(MpdOutput::from_response(&response(&enabled)).is_err());
+    }
+}
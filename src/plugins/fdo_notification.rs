@@ -1,28 +1,23 @@
+//! Sending MPD activities as notifications
 #![allow(clippy::too_many_arguments)]
-use crate::mpd::{
-    types::{MpdPlaybackState, MpdState},
-    MpdStateServer,
-};
-/// Sending MPD activities as notifications
+use super::{Plugin, PluginContext};
+use crate::mpd::types::{MpdPlaybackState, MpdState};
 use crate::types::PlayerStateChange;
 
-use anyhow::Result;
-use log::{debug, error};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::{
-    spawn,
-    sync::broadcast::Receiver,
-    sync::{Mutex, RwLock},
-    task::JoinHandle,
-    time::sleep,
-};
-use zbus::{proxy, Connection};
+use anyhow::{bail, Result};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use std::{collections::HashMap, sync::Arc};
+use zbus::proxy;
 use zvariant::Value;
 
 const DEFAULT_PLAYER_NAME: &str = "Music Player Daemon";
-const DEFAULT_MPD_ICON_PATH: &str = "/usr/share/icons/hicolor/scalable/apps/mpd.svg";
+/// Icon shown when the song has no album art, unless told otherwise
+pub const DEFAULT_MPD_ICON_PATH: &str = "/usr/share/icons/hicolor/scalable/apps/mpd.svg";
+/// How long notifications stay on screen by default, in milliseconds
+pub const DEFAULT_TIMEOUT: u32 = 5000;
 
+#[allow(missing_docs)]
 #[proxy(assume_defaults = true)]
 trait Notifications {
     /// Call the org.freedesktop.Notifications.Notify D-Bus method
@@ -37,66 +32,80 @@ trait Notifications {
         hints: &HashMap<&str, Value<'_>>,
         expire_timeout: i32,
     ) -> zbus::Result<u32>;
+
+    /// Call the org.freedesktop.Notifications.CloseNotification D-Bus method
+    fn close_notification(&self, id: u32) -> zbus::Result<()>;
 }
 
-pub struct FdoNotificationRelay<'a> {
-    proxy: NotificationsProxy<'a>,
-    mpd_event_rx: Receiver<PlayerStateChange>,
-    state: Arc<RwLock<MpdState>>,
+/// Settings of [`FdoNotificationRelay`]. They can be changed while it runs,
+/// see [`FdoNotificationRelay::settings_handle`].
+#[derive(Clone, Debug)]
+pub struct NotificationSettings {
+    /// How long notifications stay on screen, in milliseconds
+    pub timeout: u32,
+    /// Icon shown when the song has no album art
+    pub icon: String,
+}
 
-    // Settings
-    mpd_icon: String,
-    notification_timeout: u32,
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        NotificationSettings {
+            timeout: DEFAULT_TIMEOUT,
+            icon: DEFAULT_MPD_ICON_PATH.to_owned(),
+        }
+    }
+}
+
+/// Sends a desktop notification whenever playback or the current song changes
+pub struct FdoNotificationRelay {
+    proxy: Option<NotificationsProxy<'static>>,
+    settings: Arc<ArcSwap<NotificationSettings>>,
     last_notification_id: u32,
-    hints: HashMap<&'a str, Value<'a>>,
+    hints: HashMap<&'static str, Value<'static>>,
 }
 
-impl<'a> FdoNotificationRelay<'a> {
-    pub async fn new(
-        connection: &Connection,
-        client: Arc<Mutex<MpdStateServer>>,
-    ) -> Result<FdoNotificationRelay<'a>> {
-        let proxy = NotificationsProxy::new(connection).await?;
-        let client = client.lock().await;
-        let mpd_event_rx = client.get_mpd_event_rx();
-        let state = client.get_status();
+impl Default for FdoNotificationRelay {
+    fn default() -> Self {
         let mut hints = HashMap::new();
         hints.insert("urgency", Value::from(0));
 
-        let res = FdoNotificationRelay {
-            proxy,
-            mpd_event_rx,
-            state,
-            mpd_icon: DEFAULT_MPD_ICON_PATH.to_owned(),
-            notification_timeout: 5000,
+        FdoNotificationRelay {
+            proxy: None,
+            settings: Arc::default(),
             last_notification_id: 0,
             hints,
-        };
+        }
+    }
+}
 
-        Ok(res)
+impl FdoNotificationRelay {
+    /// Create a relay with default settings
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    async fn send_notification_on_event(&mut self) -> Result<()> {
-        use PlayerStateChange::*;
-        loop {
-            debug!("Waiting for MPD state change from NotificationRelay...");
-            let event = self.mpd_event_rx.recv().await?;
-            match event {
-                Playback | Song => {
-                    self.send_notification().await?;
-                }
-                _ => (),
-            }
-        }
+    /// Start with these settings
+    pub fn settings(self, settings: NotificationSettings) -> Self {
+        self.settings.store(Arc::new(settings));
+        self
+    }
+
+    /// Handle to change the settings while the relay runs
+    pub fn settings_handle(&self) -> Arc<ArcSwap<NotificationSettings>> {
+        self.settings.clone()
     }
 
-    async fn send_notification(&mut self) -> Result<()> {
-        let state = self.state.read().await;
+    async fn send_notification(&mut self, state: &MpdState) -> Result<()> {
+        let proxy = match &self.proxy {
+            Some(proxy) => proxy,
+            None => bail!("notification relay is not started"),
+        };
+        let settings = self.settings.load();
         let playback_status = state.playback_state.to_string();
-        let mut img_uri = state
-            .album_art
-            .as_ref()
-            .map_or_else(|| self.mpd_icon.clone(), |path| path.display().to_string());
+        let mut img_uri = state.album_art.as_ref().map_or_else(
+            || settings.icon.clone(),
+            |art| art.thumbnail.display().to_string(),
+        );
         img_uri.insert_str(0, "file://");
         let body = if state.playback_state == MpdPlaybackState::Stopped {
             "Playback stopped".to_string()
@@ -119,8 +128,7 @@ impl<'a> FdoNotificationRelay<'a> {
             "Unknown Song - Unknown Artist".to_string()
         };
 
-        let notification_id = self
-            .proxy
+        let notification_id = proxy
             .notify(
                 DEFAULT_PLAYER_NAME,
                 self.last_notification_id,
@@ -129,7 +137,7 @@ impl<'a> FdoNotificationRelay<'a> {
                 &body,
                 &[],
                 &self.hints,
-                self.notification_timeout as i32,
+                settings.timeout as i32,
             )
             .await?;
 
@@ -139,18 +147,37 @@ impl<'a> FdoNotificationRelay<'a> {
     }
 }
 
-pub async fn start(
-    connection: &Connection,
-    mpdclient: Arc<Mutex<MpdStateServer>>,
-) -> Result<JoinHandle<()>> {
-    let mut notification_relay = FdoNotificationRelay::new(connection, mpdclient).await?;
-    let task = spawn(async move {
-        loop {
-            if let Err(e) = notification_relay.send_notification_on_event().await {
-                error!("NotificationRelay dead, restarting. Reason: {e}");
-                sleep(crate::RETRY_INTERVAL).await;
+#[async_trait]
+impl Plugin for FdoNotificationRelay {
+    fn name(&self) -> &'static str {
+        "notification"
+    }
+
+    async fn start(&mut self, ctx: &PluginContext) -> Result<()> {
+        self.proxy = Some(NotificationsProxy::new(&ctx.connection).await?);
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, ctx: &PluginContext, event: PlayerStateChange) -> Result<()> {
+        use PlayerStateChange::*;
+        match event {
+            Playback | Song => self.send_notification(&ctx.server.get_status()).await,
+            // Put the art in the notification about this song
+            AlbumArt if self.last_notification_id != 0 => {
+                self.send_notification(&ctx.server.get_status()).await
             }
+            _ => Ok(()),
         }
-    });
-    Ok(task)
+    }
+
+    async fn shutdown(&mut self, _ctx: &PluginContext) -> Result<()> {
+        // Don't leave a notification about a player that's gone
+        if let Some(proxy) = &self.proxy {
+            if self.last_notification_id != 0 {
+                proxy.close_notification(self.last_notification_id).await?;
+                self.last_notification_id = 0;
+            }
+        }
+        Ok(())
+    }
 }
@@ -1,2 +1,257 @@
+//! Consumers of MPD state that talk over D-Bus
+//!
+//! Every plugin implements [`Plugin`] and is driven by a [`PluginHost`], which
+//! hands it the state server and the shared D-Bus connection, and feeds it
+//! every [`PlayerStateChange`]. Plugins run under a [`Supervisor`], so a plugin
+//! that fails is started again after a while.
+#[cfg(feature = "notification")]
 pub mod fdo_notification;
+#[cfg(feature = "mpris")]
 pub mod mpris2;
+
+use crate::{
+    mpd::{types::MpdStateChanged, MpdStateServer},
+    supervisor::Supervisor,
+    types::PlayerStateChange,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use std::sync::Arc;
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex},
+    task::JoinHandle,
+};
+use zbus::{Connection, ConnectionBuilder};
+
+/// What a plugin gets to work with
+#[derive(Clone)]
+pub struct PluginContext {
+    /// Handle to MPD state
+    pub server: MpdStateServer,
+    /// The session bus connection shared by all plugins
+    pub connection: Connection,
+}
+
+/// A consumer of MPD state changes
+#[async_trait]
+pub trait Plugin: Send {
+    /// Short name, used in the config file and logs
+    fn name(&self) -> &'static str;
+
+    /// Whether the daemon is useless without this plugin. If a critical
+    /// plugin keeps failing, the process exits.
+    fn critical(&self) -> bool {
+        false
+    }
+
+    /// MPD subsystems this plugin needs, on top of the playback state and the
+    /// queue that the state server always follows. Changes in them come as
+    /// [`PlayerStateChange::Mpd`] events, and keep outputs, partitions and
+    /// stickers in the state up to date. MPD only wakes up the idle
+    /// connection for subsystems some plugin needs.
+    fn subsystems(&self) -> Vec<MpdStateChanged> {
+        Vec::new()
+    }
+
+    /// Set up whatever the plugin needs. Called before any event, and again
+    /// whenever the plugin is restarted after a failure.
+    async fn start(&mut self, ctx: &PluginContext) -> Result<()>;
+
+    /// React to a state change. The new state can be read from `ctx.server`.
+    /// Returning an error restarts the plugin.
+    async fn handle_event(&mut self, ctx: &PluginContext, event: PlayerStateChange) -> Result<()>;
+
+    /// Clean up before exiting
+    async fn shutdown(&mut self, _ctx: &PluginContext) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs plugins, each in its own task
+pub struct PluginHost {
+    ctx: PluginContext,
+    plugins: Vec<Box<dyn Plugin>>,
+    label: Option<String>,
+}
+
+impl PluginHost {
+    /// Create a host that shares `connection` between its plugins
+    pub fn new(server: MpdStateServer, connection: Connection) -> Self {
+        PluginHost {
+            ctx: PluginContext { server, connection },
+            plugins: Vec::new(),
+            label: None,
+        }
+    }
+
+    /// Tell the tasks of this host apart from those of other hosts
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_owned());
+        self
+    }
+
+    /// Register a plugin. It won't do anything until [`PluginHost::start`].
+    pub fn add(&mut self, plugin: Box<dyn Plugin>) {
+        self.plugins.push(plugin);
+    }
+
+    /// Start every registered plugin under `supervisor`
+    pub fn start(self, supervisor: &Supervisor) -> RunningPlugins {
+        self.ctx.server.subscribe(&subsystems(&self.plugins));
+        let mut plugins = Vec::new();
+        let mut tasks = Vec::new();
+        for plugin in self.plugins {
+            let name = match &self.label {
+                Some(label) => format!("plugin {} ({label})", plugin.name()),
+                None => format!("plugin {}", plugin.name()),
+            };
+            let critical = plugin.critical();
+            let plugin = Arc::new(Mutex::new(plugin));
+            let ctx = self.ctx.clone();
+            let task = {
+                let plugin = plugin.clone();
+                supervisor.spawn(&name, critical, move || {
+                    run_plugin(plugin.clone(), ctx.clone())
+                })
+            };
+            plugins.push(plugin);
+            tasks.push(task);
+        }
+        RunningPlugins {
+            ctx: self.ctx,
+            plugins,
+            tasks,
+        }
+    }
+}
+
+/// Plugins started by [`PluginHost::start`]
+pub struct RunningPlugins {
+    ctx: PluginContext,
+    plugins: Vec<Arc<Mutex<Box<dyn Plugin>>>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl RunningPlugins {
+    /// Stop every plugin, then stop the state server and let every plugin
+    /// clean up with [`Plugin::shutdown`]. By then the state is offline, so
+    /// plugins can announce that playback stopped.
+    pub async fn shutdown(self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        for task in self.tasks {
+            task.await.ok();
+        }
+
+        if let Err(e) = self.ctx.server.shutdown().await {
+            warn!("Failed to shut down MPD state server: {e}");
+        }
+
+        for plugin in self.plugins {
+            let mut plugin = plugin.lock().await;
+            match plugin.shutdown(&self.ctx).await {
+                Ok(()) => debug!("Plugin {} shut down", plugin.name()),
+                Err(e) => warn!("Failed to shut down plugin {}: {e:#}", plugin.name()),
+            }
+        }
+    }
+}
+
+/// Every subsystem some of `plugins` need
+fn subsystems(plugins: &[Box<dyn Plugin>]) -> Vec<MpdStateChanged> {
+    let mut res: Vec<_> = plugins.iter().flat_map(|p| p.subsystems()).collect();
+    res.sort();
+    res.dedup();
+    res
+}
+
+async fn run_plugin(plugin: Arc<Mutex<Box<dyn Plugin>>>, ctx: PluginContext) -> Result<()> {
+    let mut plugin = plugin.lock().await;
+    // Subscribe before starting, so that no event is lost in between
+    let mut rx = ctx.server.get_mpd_event_rx();
+    plugin
+        .start(&ctx)
+        .await
+        .with_context(|| format!("failed to start plugin {}", plugin.name()))?;
+    info!("Plugin {} started", plugin.name());
+
+    loop {
+        debug!(
+            "Waiting for MPD state change from plugin {}...",
+            plugin.name()
+        );
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(n)) => {
+                warn!("Plugin {} missed {n} events", plugin.name());
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        plugin
+            .handle_event(&ctx, event.clone())
+            .await
+            .with_context(|| format!("failed to handle {event:?}"))?;
+    }
+}
+
+/// Connect to the session bus
+pub async fn session_connection() -> Result<Connection> {
+    let connection = ConnectionBuilder::session()
+        .context("Failed to connect to D-Bus session bus. Is $DBUS_SESSION_BUS_ADDRESS set to the correct address?")?
+        .build()
+        .await?;
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpd::idle_subsystems;
+    use MpdStateChanged::*;
+
+    /// A plugin that only needs some subsystems
+    struct Needs(Vec<MpdStateChanged>);
+
+    #[async_trait]
+    impl Plugin for Needs {
+        fn name(&self) -> &'static str {
+            "needs"
+        }
+
+        fn subsystems(&self) -> Vec<MpdStateChanged> {
+            self.0.clone()
+        }
+
+        async fn start(&mut self, _ctx: &PluginContext) -> Result<()> {
+            Ok(())
+        }
+
+        async fn handle_event(&mut self, _: &PluginContext, _: PlayerStateChange) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn idle_on_what_plugins_need() {
+        let plugins: Vec<Box<dyn Plugin>> = vec![
+            Box::new(Needs(vec![Output, Database])),
+            Box::new(Needs(vec![Output])),
+            Box::new(Needs(Vec::new())),
+        ];
+        assert_eq!(subsystems(&plugins), [Database, Output]);
+        let idle = idle_subsystems(subsystems(&plugins));
+        assert!(idle.contains(&Player) && idle.contains(&CurrentPlaylist));
+        assert!(idle.contains(&Output) && idle.contains(&Database));
+        assert!(!idle.contains(&Sticker));
+        assert!(!idle.contains(&Partition));
+
+        // Without plugins, only the playback state and the queue
+        let idle = idle_subsystems(subsystems(&[]));
+        assert_eq!(idle.len(), 4);
+        assert!(!idle.contains(&Sticker));
+    }
+}
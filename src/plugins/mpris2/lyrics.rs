@@ -0,0 +1,52 @@
+/// Lyrics of the current song (me.szclsya.mpdris2.Lyrics), which MPRIS has
+/// no place for apart from the plain text in `xesam:asText`
+use crate::mpd::MpdStateServer;
+
+use zbus::interface;
+
+pub struct LyricsInterface {
+    mpdclient: MpdStateServer,
+}
+
+impl LyricsInterface {
+    pub fn new(mpdclient: MpdStateServer) -> Self {
+        LyricsInterface { mpdclient }
+    }
+}
+
+#[interface(name = "me.szclsya.mpdris2.Lyrics")]
+impl LyricsInterface {
+    /// Synced lines with their start time in microseconds, empty if the
+    /// lyrics aren't synced
+    #[zbus(property, name = "Lines")]
+    async fn lines(&self) -> Vec<(i64, String)> {
+        let state = self.mpdclient.get_status();
+        let Some(lyrics) = &state.lyrics else {
+            return Vec::new();
+        };
+        lyrics
+            .lines
+            .iter()
+            .map(|line| (line.time.as_micros() as i64, line.text.clone()))
+            .collect()
+    }
+
+    /// The line being sung
+    #[zbus(property, name = "CurrentLine")]
+    async fn current_line(&self) -> String {
+        let state = self.mpdclient.get_status();
+        match (&state.lyrics, state.lyric_line) {
+            (Some(lyrics), Some(i)) => lyrics.lines.get(i).map_or("", |l| &l.text).to_owned(),
+            _ => String::new(),
+        }
+    }
+
+    /// Index of the line being sung in `Lines`, -1 before the first one
+    #[zbus(property, name = "CurrentLineIndex")]
+    async fn current_line_index(&self) -> i32 {
+        self.mpdclient
+            .get_status()
+            .lyric_line
+            .map_or(-1, |i| i as i32)
+    }
+}
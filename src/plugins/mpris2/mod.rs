@@ -1,50 +1,294 @@
+//! The MPRIS2 object (`org.mpris.MediaPlayer2.mpd`)
+mod lyrics;
 mod notifier;
-pub mod player;
+mod outputs;
+mod partitions;
+mod player;
+mod playlist_file;
+mod queue;
+mod rating;
 mod root;
-pub mod tracklist;
+mod tracklist;
 mod utils;
+pub use utils::{to_mpris_metadata, TagKind, TagMapping};
 
-const BUS_NAME: &str = "org.mpris.MediaPlayer2.mpd";
+/// Bus name claimed unless told otherwise
+pub const DEFAULT_BUS_NAME: &str = "org.mpris.MediaPlayer2.mpd";
+/// Every MPRIS player has to own a bus name under this prefix
+pub const BUS_NAME_PREFIX: &str = "org.mpris.MediaPlayer2.";
+/// Name shown to the user unless told otherwise
+pub const DEFAULT_IDENTITY: &str = "Music Player Daemon (mpdris2-rs)";
 const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
 
-use crate::mpd::MpdStateServer;
-use notifier::notify_loop;
+use super::{Plugin, PluginContext};
+use crate::{mpd::types::MpdStateChanged, types::PlayerStateChange};
+use lyrics::LyricsInterface;
+use notifier::notify;
+use outputs::OutputsInterface;
+use partitions::PartitionsInterface;
 use player::PlayerInterface;
+use queue::QueueInterface;
+use rating::RatingInterface;
 use root::RootInterface;
 use tracklist::TracklistInterface;
 
-use anyhow::{Context, Result};
-use log::error;
-use std::sync::Arc;
-use tokio::{spawn, sync::Mutex, task::JoinHandle};
-use zbus::{Connection, ConnectionBuilder};
-
-pub async fn start(
-    mpd_state_server: Arc<Mutex<MpdStateServer>>,
-) -> Result<(Connection, JoinHandle<()>)> {
-    let root_interface = RootInterface::default();
-    let player_interface = PlayerInterface::new(mpd_state_server.clone()).await;
-    let tracklist_interface = TracklistInterface::new(mpd_state_server.clone());
-
-    let connection = ConnectionBuilder::session().context("Failed to connect to D-Bus session bus. Is $DBUS_SESSION_BUS_ADDRESS set to the correct address?")?
-        .name(BUS_NAME)?
-        .serve_at(OBJECT_PATH, root_interface)?
-        .serve_at(OBJECT_PATH, player_interface)?
-        .serve_at(OBJECT_PATH, tracklist_interface)?
-        .build()
-        .await?;
-
-    let connection2 = connection.clone();
-    let client = mpd_state_server.clone();
-    let mut rx = mpd_state_server.lock().await.get_mpd_event_rx();
-
-    let notifier = spawn(async move {
-        loop {
-            if let Err(e) = notify_loop(&connection2, &mut rx, &client).await {
-                error!("D-Bus property change notifier dead, restarting. Reason: {e}");
+use anyhow::{bail, Context, Result};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, str::FromStr, sync::Arc};
+use zbus::names::WellKnownName;
+
+/// How the MPRIS player behaves while MPD is unreachable
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OfflinePolicy {
+    /// Keep the bus name, but show a stopped player that can't be controlled
+    #[default]
+    Stopped,
+    /// Release the bus name until MPD comes back
+    Release,
+}
+
+impl FromStr for OfflinePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stopped" => Ok(OfflinePolicy::Stopped),
+            "release" => Ok(OfflinePolicy::Release),
+            _ => Err(format!(
+                "unknown offline policy {s}, expect stopped or release"
+            )),
+        }
+    }
+}
+
+impl Display for OfflinePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            OfflinePolicy::Stopped => "stopped",
+            OfflinePolicy::Release => "release",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Sets up the MPRIS2 object (as a [`Plugin`])
+///
+/// ```no_run
+/// # async fn example(mut host: mpdris2_rs::plugins::PluginHost, server: mpdris2_rs::mpd::MpdStateServer) {
+/// use mpdris2_rs::plugins::mpris2::{Mpris2Builder, OfflinePolicy};
+///
+/// let plugin = Mpris2Builder::new()
+///     .offline_policy(OfflinePolicy::Release)
+///     .build()
+///     .unwrap();
+/// host.add(Box::new(plugin));
+/// # }
+/// ```
+pub struct Mpris2Builder {
+    offline_policy: OfflinePolicy,
+    bus_name: String,
+    identity: String,
+    tags: Vec<TagMapping>,
+}
+
+impl Default for Mpris2Builder {
+    fn default() -> Self {
+        Mpris2Builder {
+            offline_policy: OfflinePolicy::default(),
+            bus_name: DEFAULT_BUS_NAME.to_owned(),
+            identity: DEFAULT_IDENTITY.to_owned(),
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl Mpris2Builder {
+    /// Start with default settings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bus name to claim. Must start with [`BUS_NAME_PREFIX`].
+    /// Defaults to [`DEFAULT_BUS_NAME`]
+    pub fn bus_name(mut self, bus_name: &str) -> Self {
+        self.bus_name = bus_name.to_owned();
+        self
+    }
+
+    /// Name of the player shown to the user. Defaults to [`DEFAULT_IDENTITY`]
+    pub fn identity(mut self, identity: &str) -> Self {
+        self.identity = identity.to_owned();
+        self
+    }
+
+    /// What to do while MPD is unreachable. Defaults to [`OfflinePolicy::Stopped`]
+    pub fn offline_policy(mut self, offline_policy: OfflinePolicy) -> Self {
+        self.offline_policy = offline_policy;
+        self
+    }
+
+    /// Extra MPD tags to put in metadata, on top of the standard ones
+    pub fn tags(mut self, tags: Vec<TagMapping>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Build the plugin. Fails if the bus name isn't a valid MPRIS bus name.
+    pub fn build(self) -> Result<Mpris2Plugin> {
+        check_bus_name(&self.bus_name)?;
+        Ok(Mpris2Plugin {
+            offline_policy: self.offline_policy,
+            bus_name: self.bus_name,
+            identity: self.identity,
+            tags: Arc::new(ArcSwap::from_pointee(self.tags)),
+        })
+    }
+}
+
+/// Bus name of the player of another MPD partition, like
+/// `org.mpris.MediaPlayer2.mpd.kitchen`. Characters bus names can't have
+/// become `_`.
+pub fn partition_bus_name(bus_name: &str, partition: &str) -> String {
+    let mut element: String = partition
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
             }
+        })
+        .collect();
+    if element.starts_with(|c: char| c.is_ascii_digit()) {
+        element.insert(0, '_');
+    }
+    format!("{bus_name}.{element}")
+}
+
+/// Check that `bus_name` can be used by a MPRIS player
+pub fn check_bus_name(bus_name: &str) -> Result<()> {
+    if !bus_name.starts_with(BUS_NAME_PREFIX) || bus_name.len() == BUS_NAME_PREFIX.len() {
+        bail!("MPRIS bus name {bus_name} doesn't start with {BUS_NAME_PREFIX}");
+    }
+    WellKnownName::try_from(bus_name)
+        .with_context(|| format!("{bus_name} is not a valid bus name"))?;
+    Ok(())
+}
+
+/// Serves the MPRIS2 object and relays state changes as property change signals
+pub struct Mpris2Plugin {
+    offline_policy: OfflinePolicy,
+    bus_name: String,
+    identity: String,
+    tags: Arc<ArcSwap<Vec<TagMapping>>>,
+}
+
+impl Mpris2Plugin {
+    /// Handle to change the extra tag mappings while the plugin runs
+    pub fn tags_handle(&self) -> Arc<ArcSwap<Vec<TagMapping>>> {
+        self.tags.clone()
+    }
+}
+
+#[async_trait]
+impl Plugin for Mpris2Plugin {
+    fn name(&self) -> &'static str {
+        "mpris"
+    }
+
+    fn critical(&self) -> bool {
+        true
+    }
+
+    fn subsystems(&self) -> Vec<MpdStateChanged> {
+        // For the outputs, partitions and rating interfaces
+        vec![
+            MpdStateChanged::Output,
+            MpdStateChanged::Partition,
+            MpdStateChanged::Sticker,
+        ]
+    }
+
+    async fn start(&mut self, ctx: &PluginContext) -> Result<()> {
+        let server = ctx.server.clone();
+        let object_server = ctx.connection.object_server();
+        object_server
+            .at(
+                OBJECT_PATH,
+                RootInterface::new(server.clone(), self.identity.clone()),
+            )
+            .await?;
+        object_server
+            .at(
+                OBJECT_PATH,
+                PlayerInterface::new(server.clone(), self.tags.clone()),
+            )
+            .await?;
+        object_server
+            .at(
+                OBJECT_PATH,
+                TracklistInterface::new(server.clone(), self.tags.clone()),
+            )
+            .await?;
+        object_server
+            .at(OBJECT_PATH, LyricsInterface::new(server.clone()))
+            .await?;
+        object_server
+            .at(OBJECT_PATH, QueueInterface::new(server.clone()))
+            .await?;
+        object_server
+            .at(OBJECT_PATH, OutputsInterface::new(server.clone()))
+            .await?;
+        object_server
+            .at(OBJECT_PATH, PartitionsInterface::new(server.clone()))
+            .await?;
+        object_server
+            .at(OBJECT_PATH, RatingInterface::new(server.clone()))
+            .await?;
+
+        if self.offline_policy == OfflinePolicy::Release && !server.get_status().online {
+            info!(
+                "MPD is unreachable, not requesting {} until it comes back",
+                self.bus_name
+            );
+        } else {
+            ctx.connection
+                .request_name(self.bus_name.as_str())
+                .await
+                .with_context(|| format!("Failed to claim {}", self.bus_name))?;
         }
-    });
+        Ok(())
+    }
 
-    Ok((connection, notifier))
+    async fn handle_event(&mut self, ctx: &PluginContext, event: PlayerStateChange) -> Result<()> {
+        notify(
+            &ctx.connection,
+            event,
+            &ctx.server,
+            self.offline_policy,
+            &self.bus_name,
+        )
+        .await
+    }
+
+    async fn shutdown(&mut self, ctx: &PluginContext) -> Result<()> {
+        // The state is offline by now, let clients see that we stopped
+        for event in [PlayerStateChange::Playback, PlayerStateChange::Song] {
+            notify(
+                &ctx.connection,
+                event,
+                &ctx.server,
+                self.offline_policy,
+                &self.bus_name,
+            )
+            .await?;
+        }
+        info!("Releasing {}", self.bus_name);
+        ctx.connection.release_name(self.bus_name.as_str()).await?;
+        Ok(())
+    }
 }
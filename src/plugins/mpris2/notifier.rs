@@ -1,17 +1,21 @@
-use super::{PlayerInterface, TracklistInterface, OBJECT_PATH};
+use super::{
+    LyricsInterface, OfflinePolicy, OutputsInterface, PartitionsInterface, PlayerInterface,
+    QueueInterface, TracklistInterface, OBJECT_PATH,
+};
 use crate::{mpd::MpdStateServer, types::PlayerStateChange};
 
 use anyhow::Result;
-use log::debug;
-use std::sync::Arc;
-use tokio::sync::{broadcast::Receiver, Mutex};
+use log::info;
 use zbus::Connection;
 use zvariant::ObjectPath;
 
-pub async fn notify_loop(
+/// Turn a MPD state change into D-Bus property change signals
+pub async fn notify(
     c: &Connection,
-    rx: &mut Receiver<PlayerStateChange>,
-    client: &Arc<Mutex<MpdStateServer>>,
+    event: PlayerStateChange,
+    client: &MpdStateServer,
+    offline_policy: OfflinePolicy,
+    bus_name: &str,
 ) -> Result<()> {
     use PlayerStateChange::*;
     let player_iface_ref = c
@@ -22,64 +26,123 @@ pub async fn notify_loop(
         .object_server()
         .interface::<_, TracklistInterface>(OBJECT_PATH)
         .await?;
+    let queue_iface_ref = c
+        .object_server()
+        .interface::<_, QueueInterface>(OBJECT_PATH)
+        .await?;
+    let lyrics_iface_ref = c
+        .object_server()
+        .interface::<_, LyricsInterface>(OBJECT_PATH)
+        .await?;
 
-    loop {
-        debug!("Waiting for MPD state change from org.mpris2.MediaPlayer2...");
-        let signal = rx.recv().await;
-
-        let player_iface = player_iface_ref.get_mut().await;
-        let player_ctxt = player_iface_ref.signal_context();
-        let tracklist_ctxt = tracklist_iface_ref.signal_context();
-        if let Ok(s) = signal {
-            match s {
-                Playback => {
-                    player_iface.playback_status_changed(player_ctxt).await?;
-                }
-                Loop => {
-                    player_iface.loop_status_changed(player_ctxt).await?;
-                    player_iface.can_go_next_changed(player_ctxt).await?;
+    let player_iface = player_iface_ref.get().await;
+    let player_ctxt = player_iface_ref.signal_context();
+    let tracklist_ctxt = tracklist_iface_ref.signal_context();
+    let queue_iface = queue_iface_ref.get().await;
+    let queue_ctxt = queue_iface_ref.signal_context();
+    let lyrics_iface = lyrics_iface_ref.get().await;
+    let lyrics_ctxt = lyrics_iface_ref.signal_context();
+    match event {
+        Connection => {
+            let online = client.get_status().online;
+            if offline_policy == OfflinePolicy::Release {
+                if online {
+                    info!("MPD is back, requesting {bus_name}");
+                    c.request_name(bus_name).await?;
+                } else {
+                    info!("MPD is unreachable, releasing {bus_name}");
+                    c.release_name(bus_name).await?;
                 }
-                Shuffle => {
-                    player_iface.shuffle_changed(player_ctxt).await?;
-                }
-                Volume => {
-                    player_iface.volume_changed(player_ctxt).await?;
-                }
-                Song => {
-                    player_iface.metadata_changed(player_ctxt).await?;
-                    player_iface.playback_status_changed(player_ctxt).await?;
-                    player_iface.can_go_next_changed(player_ctxt).await?;
-                }
-                Tracklist => {
-                    use super::tracklist::{extract_ids_from_metadata, get_current_playlist};
-                    if let Ok(tracklist) = get_current_playlist(client.clone()).await {
-                        let ids: Vec<ObjectPath<'_>> = tracklist
-                            .iter()
-                            .filter_map(|song| extract_ids_from_metadata(song).ok())
-                            .collect();
+            }
+            player_iface.can_control_changed(player_ctxt).await?;
+            player_iface.can_play_changed(player_ctxt).await?;
+            player_iface.can_pause_changed(player_ctxt).await?;
+            player_iface.can_seek_changed(player_ctxt).await?;
+            player_iface.can_go_previous_changed(player_ctxt).await?;
+        }
+        Playback => {
+            player_iface.playback_status_changed(player_ctxt).await?;
+            queue_iface.remaining_time_changed(queue_ctxt).await?;
+        }
+        Loop => {
+            player_iface.loop_status_changed(player_ctxt).await?;
+            player_iface.can_go_next_changed(player_ctxt).await?;
+        }
+        Shuffle => {
+            player_iface.shuffle_changed(player_ctxt).await?;
+        }
+        Volume => {
+            player_iface.volume_changed(player_ctxt).await?;
+        }
+        Song => {
+            player_iface.metadata_changed(player_ctxt).await?;
+            player_iface.playback_status_changed(player_ctxt).await?;
+            player_iface.can_go_next_changed(player_ctxt).await?;
+            queue_iface.remaining_time_changed(queue_ctxt).await?;
+            lyrics_iface.lines_changed(lyrics_ctxt).await?;
+            lyrics_iface.current_line_changed(lyrics_ctxt).await?;
+            lyrics_iface.current_line_index_changed(lyrics_ctxt).await?;
+        }
+        AlbumArt | Stickers => {
+            player_iface.metadata_changed(player_ctxt).await?;
+        }
+        Lyrics => {
+            player_iface.metadata_changed(player_ctxt).await?;
+            lyrics_iface.lines_changed(lyrics_ctxt).await?;
+        }
+        LyricLine => {
+            lyrics_iface.current_line_changed(lyrics_ctxt).await?;
+            lyrics_iface.current_line_index_changed(lyrics_ctxt).await?;
+        }
+        Tracklist => {
+            queue_iface.total_time_changed(queue_ctxt).await?;
+            queue_iface.remaining_time_changed(queue_ctxt).await?;
+            use super::tracklist::{extract_ids_from_metadata, get_current_playlist};
+            let state = client.get_status();
+            // Only the ids are needed, extra tags don't matter
+            if let Ok(tracklist) = get_current_playlist(&state, &[]) {
+                let ids: Vec<ObjectPath<'_>> = tracklist
+                    .iter()
+                    .filter_map(|song| extract_ids_from_metadata(song).ok())
+                    .collect();
 
-                        let current_object_path = if ids.is_empty() {
-                            ObjectPath::try_from("/org/mpris/MediaPlayer2/TrackList/NoTrack")
-                                .unwrap()
-                        } else {
-                            let client = client.lock().await;
-                            let state = client.get_status();
-                            let state = state.read().await;
-                            let current_pos = state.song.unwrap_or((0, 0)).0;
-                            ids.get(current_pos as usize).unwrap().clone()
-                        };
+                // The current song may be missing from the list for a moment,
+                // while the queue and the status are updated one after another
+                let current_pos = state.song.map_or(0, |(pos, _)| pos as usize);
+                let current_object_path = match ids.get(current_pos) {
+                    Some(id) => id.clone(),
+                    None => ObjectPath::from_static_str_unchecked(
+                        "/org/mpris/MediaPlayer2/TrackList/NoTrack",
+                    ),
+                };
 
-                        TracklistInterface::track_list_replaced(
-                            tracklist_ctxt,
-                            ids,
-                            current_object_path,
-                        )
-                        .await
-                        .ok();
-                    }
-                }
-                NextSong => (),
+                TracklistInterface::track_list_replaced(tracklist_ctxt, ids, current_object_path)
+                    .await
+                    .ok();
             }
         }
+        Outputs => {
+            let outputs_iface_ref = c
+                .object_server()
+                .interface::<_, OutputsInterface>(OBJECT_PATH)
+                .await?;
+            let outputs_iface = outputs_iface_ref.get().await;
+            outputs_iface
+                .outputs_changed(outputs_iface_ref.signal_context())
+                .await?;
+        }
+        Partitions => {
+            let partitions_iface_ref = c
+                .object_server()
+                .interface::<_, PartitionsInterface>(OBJECT_PATH)
+                .await?;
+            let partitions_iface = partitions_iface_ref.get().await;
+            let ctxt = partitions_iface_ref.signal_context();
+            partitions_iface.partition_changed(ctxt).await?;
+            partitions_iface.partitions_changed(ctxt).await?;
+        }
+        NextSong | Mpd(_) => (),
     }
+
+    Ok(())
 }
@@ -0,0 +1,111 @@
+/// Audio outputs of MPD (me.szclsya.mpdris2.Outputs)
+use super::utils::*;
+use crate::mpd::MpdStateServer;
+
+use log::error;
+use std::collections::HashMap;
+use zbus::{fdo, interface};
+
+pub struct OutputsInterface {
+    mpdclient: MpdStateServer,
+}
+
+impl OutputsInterface {
+    pub fn new(mpdclient: MpdStateServer) -> Self {
+        OutputsInterface { mpdclient }
+    }
+
+    async fn command(&self, method: &str, cmd: &str) -> fdo::Result<()> {
+        match self.mpdclient.issue_command(cmd).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("me.szclsya.mpdris2.Outputs.{method} failed: {e}");
+                Err(to_fdo_err(e))
+            }
+        }
+    }
+}
+
+#[interface(name = "me.szclsya.mpdris2.Outputs")]
+impl OutputsInterface {
+    #[zbus(name = "EnableOutput")]
+    async fn enable_output(&self, id: u32) -> fdo::Result<()> {
+        self.command("EnableOutput", &format!("enableoutput {id}"))
+            .await
+    }
+
+    #[zbus(name = "DisableOutput")]
+    async fn disable_output(&self, id: u32) -> fdo::Result<()> {
+        self.command("DisableOutput", &format!("disableoutput {id}"))
+            .await
+    }
+
+    #[zbus(name = "ToggleOutput")]
+    async fn toggle_output(&self, id: u32) -> fdo::Result<()> {
+        self.command("ToggleOutput", &format!("toggleoutput {id}"))
+            .await
+    }
+
+    /// Change a runtime setting of an output, see the attributes in `Outputs`
+    #[zbus(name = "SetOutputAttribute")]
+    async fn set_output_attribute(&self, id: u32, name: &str, value: &str) -> fdo::Result<()> {
+        let cmd = outputset_command(id, name, value).map_err(|e| {
+            error!("me.szclsya.mpdris2.Outputs.SetOutputAttribute failed: {e}");
+            e
+        })?;
+        self.command("SetOutputAttribute", &cmd).await
+    }
+
+    /// Id, name, plugin, whether it's enabled and attributes of every output
+    #[zbus(property, name = "Outputs")]
+    async fn outputs(&self) -> Vec<(u32, String, String, bool, HashMap<String, String>)> {
+        let state = self.mpdclient.get_status();
+        state
+            .outputs
+            .iter()
+            .map(|o| {
+                let attributes = o.attributes.iter().cloned().collect();
+                (
+                    o.id,
+                    o.name.clone(),
+                    o.plugin.clone(),
+                    o.enabled,
+                    attributes,
+                )
+            })
+            .collect()
+    }
+}
+
+/// `outputset` command that sets attribute `name` of output `id` to `value`
+fn outputset_command(id: u32, name: &str, value: &str) -> fdo::Result<String> {
+    Ok(format!(
+        "outputset {id} {} {}",
+        quote_arg(name)?,
+        quote_arg(value)?
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outputset_commands() {
+        assert_eq!(
+            outputset_command(1, "dop", "1").unwrap(),
+            r#"outputset 1 "dop" "1""#
+        );
+        assert_eq!(
+            outputset_command(0, "allowed_formats", "").unwrap(),
+            r#"outputset 0 "allowed_formats" """#
+        );
+        // A second command must not sneak in
+        for (name, value) in [("dop\nclear", "1"), ("dop", "1\nclear"), ("dop", "1\r")] {
+            assert!(matches!(
+                outputset_command(0, name, value),
+                Err(fdo::Error::InvalidArgs(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,33 @@
+/// MPD partitions (me.szclsya.mpdris2.Partitions)
+use crate::mpd::MpdStateServer;
+
+use zbus::interface;
+
+pub struct PartitionsInterface {
+    mpdclient: MpdStateServer,
+}
+
+impl PartitionsInterface {
+    pub fn new(mpdclient: MpdStateServer) -> Self {
+        PartitionsInterface { mpdclient }
+    }
+}
+
+#[interface(name = "me.szclsya.mpdris2.Partitions")]
+impl PartitionsInterface {
+    /// The partition this player follows
+    #[zbus(property, name = "Partition")]
+    async fn partition(&self) -> String {
+        let state = self.mpdclient.get_status();
+        state
+            .partition
+            .clone()
+            .unwrap_or_else(|| "default".to_owned())
+    }
+
+    /// Every partition of MPD
+    #[zbus(property, name = "Partitions")]
+    async fn partitions(&self) -> Vec<String> {
+        Vec::clone(&self.mpdclient.get_status().partitions)
+    }
+}
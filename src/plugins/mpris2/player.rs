@@ -1,117 +1,131 @@
-use super::utils::*;
+use super::{playlist_file::PlaylistFormat, queue::import, utils::*};
 /// Player interface (org.mpris.MediaPlayer2.Player) implementation
 use crate::mpd::{types::*, MpdStateServer};
 
-use log::{debug, error};
+use arc_swap::ArcSwap;
+use log::error;
 use std::{collections::HashMap, sync::Arc, time::Duration};
-use tokio::sync::{Mutex, RwLock};
-use zbus::{interface, SignalContext};
+use zbus::{fdo, interface, SignalContext};
 use zvariant::{ObjectPath, Value};
 
 pub struct PlayerInterface {
-    mpdclient: Arc<Mutex<MpdStateServer>>,
-    mpd_state: Arc<RwLock<MpdState>>,
+    mpdclient: MpdStateServer,
+    tags: Arc<ArcSwap<Vec<TagMapping>>>,
 }
 
 impl PlayerInterface {
-    pub async fn new(mpdclient: Arc<Mutex<MpdStateServer>>) -> Self {
-        PlayerInterface {
-            mpd_state: mpdclient.clone().lock().await.get_status(),
-            mpdclient,
-        }
+    pub fn new(mpdclient: MpdStateServer, tags: Arc<ArcSwap<Vec<TagMapping>>>) -> Self {
+        PlayerInterface { mpdclient, tags }
     }
 }
 
 #[interface(name = "org.mpris.MediaPlayer2.Player")]
 impl PlayerInterface {
     #[zbus(name = "Play")]
-    async fn play(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) {
-        let mut client = self.mpdclient.lock().await;
-        match client.issue_command("play").await {
+    async fn play(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<()> {
+        match self.mpdclient.issue_command("play").await {
             Ok(_) => {
                 PlayerInterface::playback_status_changed(self, &ctxt)
                     .await
                     .ok();
-                client.update_status().await.ok();
+                Ok(())
             }
             Err(e) => {
                 error!("org.mpris.MediaPlayer2.Player.Play failed: {e}");
+                Err(to_fdo_err(e))
             }
         }
     }
 
     #[zbus(name = "Pause")]
-    async fn pause(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) {
-        match self.mpdclient.lock().await.issue_command("pause 1").await {
+    async fn pause(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<()> {
+        match self.mpdclient.issue_command("pause 1").await {
             Ok(_) => {
                 PlayerInterface::playback_status_changed(self, &ctxt)
                     .await
                     .ok();
+                Ok(())
             }
             Err(e) => {
                 error!("org.mpris.MediaPlayer2.Player.Pause failed: {e}");
+                Err(to_fdo_err(e))
             }
         }
     }
 
     #[zbus(name = "PlayPause")]
-    async fn play_pause(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) {
-        match self.mpdclient.lock().await.issue_command("pause").await {
+    async fn play_pause(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<()> {
+        match self.mpdclient.issue_command("pause").await {
             Ok(_) => {
                 PlayerInterface::playback_status_changed(self, &ctxt)
                     .await
                     .ok();
+                Ok(())
             }
             Err(e) => {
-                error!("org.mpris.MediaPlayer2.Player.Play failed: {e}");
+                error!("org.mpris.MediaPlayer2.Player.PlayPause failed: {e}");
+                Err(to_fdo_err(e))
             }
         }
     }
 
     #[zbus(name = "Next")]
-    async fn next(&self) {
-        self.mpdclient.lock().await.issue_command("next").await.ok();
+    async fn next(&self) -> fdo::Result<()> {
+        if let Err(e) = self.mpdclient.issue_command("next").await {
+            error!("org.mpris.MediaPlayer2.Player.Next failed: {e}");
+            return Err(to_fdo_err(e));
+        }
+        Ok(())
     }
 
     #[zbus(name = "Previous")]
-    async fn previous(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) {
-        let state = self.mpd_state.read().await;
+    async fn previous(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<()> {
+        let state = self.mpdclient.get_status();
         let mut cmd = "previous";
-        if let MpdPlaybackState::Playing(state) = &state.playback_state {
-            if let Some(elapsed) = state.elapsed {
-                if elapsed.as_secs_f32() > 3.0 {
-                    cmd = "seekcur 0";
-                }
+        if let MpdPlaybackState::Playing(_) = &state.playback_state {
+            if state.position().as_secs_f32() > 3.0 {
+                cmd = "seekcur 0";
             }
         }
 
-        match self.mpdclient.lock().await.issue_command(cmd).await {
+        match self.mpdclient.issue_command(cmd).await {
             Ok(_) => {
                 if cmd == "seekcur 0" {
                     PlayerInterface::seeked(&ctxt, 0).await.ok();
                 }
+                Ok(())
             }
             Err(e) => {
                 error!("org.mpris.MediaPlayer2.Player.Previous failed: {e}");
+                Err(to_fdo_err(e))
             }
         }
     }
 
     #[zbus(name = "Stop")]
-    async fn stop(&self) {
-        self.mpdclient.lock().await.issue_command("stop").await.ok();
+    async fn stop(&self) -> fdo::Result<()> {
+        if let Err(e) = self.mpdclient.issue_command("stop").await {
+            error!("org.mpris.MediaPlayer2.Player.Stop failed: {e}");
+            return Err(to_fdo_err(e));
+        }
+        Ok(())
     }
 
     #[zbus(name = "Seek")]
-    async fn seek(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>, ms: i64) {
+    async fn seek(
+        &self,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        ms: i64,
+    ) -> fdo::Result<()> {
         let symbol = if ms > 0 { '+' } else { '-' };
         let t = Duration::from_micros(ms.unsigned_abs());
-        let cmd = format!("seekcur {symbol}{}", t.as_secs());
-        if let Err(e) = self.mpdclient.lock().await.issue_command(&cmd).await {
+        let cmd = format!("seekcur {symbol}{}", t.as_secs_f64());
+        if let Err(e) = self.mpdclient.issue_command(&cmd).await {
             error!("org.mpris.MediaPlayer2.Player.Seek failed: {}", e);
-        } else {
-            PlayerInterface::seeked(&ctxt, ms).await.ok();
+            return Err(to_fdo_err(e));
         }
+        PlayerInterface::seeked(&ctxt, ms).await.ok();
+        Ok(())
     }
 
     #[zbus(signal)]
@@ -123,48 +137,106 @@ impl PlayerInterface {
         #[zbus(signal_context)] ctxt: SignalContext<'_>,
         track_id: ObjectPath<'_>,
         position: i64,
-    ) {
-        let state = self.mpd_state.read().await;
+    ) -> fdo::Result<()> {
+        let state = self.mpdclient.get_status();
         let song = state.song.map(|(_, id)| id);
-        if song == object_path_to_id(&track_id) {
-            let pos = Duration::from_micros(position as u64);
-            let cmd = format!("seekcur {}", pos.as_secs());
-            if let Err(e) = self.mpdclient.lock().await.issue_command(&cmd).await {
-                error!("org.mpris.MediaPlayer2.Player.SetPosition failed: {}", e);
-            } else {
-                PlayerInterface::seeked(&ctxt, position).await.ok();
-            }
-        } else {
-            debug!("Wrong song object id: {}", track_id);
+        if song.is_none() || song != object_path_to_id(&track_id) {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "{track_id} is not the current track"
+            )));
+        }
+        if position < 0 {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Invalid position {position}"
+            )));
+        }
+
+        let pos = Duration::from_micros(position as u64);
+        let cmd = format!("seekcur {}", pos.as_secs_f64());
+        if let Err(e) = self.mpdclient.issue_command(&cmd).await {
+            error!("org.mpris.MediaPlayer2.Player.SetPosition failed: {}", e);
+            return Err(to_fdo_err(e));
         }
+        PlayerInterface::seeked(&ctxt, position).await.ok();
+        Ok(())
     }
 
     #[zbus(name = "OpenUri")]
-    async fn open_uri(&self, uri: &str) {
-        let cmd = format!("add {}", uri);
-        self.mpdclient.lock().await.issue_command(&cmd).await.ok();
+    async fn open_uri(&self, uri: &str) -> fdo::Result<()> {
+        // Local playlist files are read here, remote ones are left to MPD
+        let is_playlist = PlaylistFormat::from_path(uri).is_some();
+        if is_playlist && file_url_to_path(uri).is_ok() {
+            let (pos, added) = import(&self.mpdclient, uri).await.map_err(|e| {
+                error!("org.mpris.MediaPlayer2.Player.OpenUri failed: {e}");
+                e
+            })?;
+            if added == 0 {
+                return Err(fdo::Error::InvalidArgs(format!("Nothing to play in {uri}")));
+            }
+            let cmd = format!("play {pos}");
+            return self
+                .mpdclient
+                .issue_command(&cmd)
+                .await
+                .map(|_| ())
+                .map_err(|e| {
+                    error!("org.mpris.MediaPlayer2.Player.OpenUri failed: {e}");
+                    to_fdo_err(e)
+                });
+        }
+
+        let state = self.mpdclient.get_status();
+        let mpd_uri = from_url(uri, &state).map_err(|e| {
+            error!("org.mpris.MediaPlayer2.Player.OpenUri failed: {e}");
+            fdo::Error::NotSupported(e)
+        })?;
+        let arg = quote_arg(&mpd_uri).map_err(|e| {
+            error!("org.mpris.MediaPlayer2.Player.OpenUri failed: {e}");
+            e
+        })?;
+        // Whatever is added ends up at the end of the queue, play the first of it
+        let add = async {
+            let pos = queue_end(&self.mpdclient).await?;
+            let cmd = if is_playlist { "load" } else { "add" };
+            self.mpdclient
+                .issue_command(&format!("{cmd} {arg}"))
+                .await?;
+            self.mpdclient.issue_command(&format!("play {pos}")).await
+        };
+        if let Err(e) = add.await {
+            error!("org.mpris.MediaPlayer2.Player.OpenUri failed: {e}");
+            return Err(to_fdo_err(e));
+        }
+        Ok(())
     }
 
     #[zbus(property, name = "PlaybackStatus")]
     async fn playback_status(&self) -> String {
-        self.mpd_state.read().await.playback_state.to_string()
+        self.mpdclient.get_status().playback_state.to_string()
     }
 
     #[zbus(property, name = "LoopStatus")]
     async fn loop_status(&self) -> String {
-        self.mpd_state.read().await.loop_state.to_string()
+        self.mpdclient.get_status().loop_state.to_string()
     }
 
     #[zbus(property, name = "LoopStatus")]
-    async fn set_loop_status(&self, state: String) {
-        let commands = match MpdLoopState::from_str(&state) {
+    async fn set_loop_status(&self, state: String) -> zbus::Result<()> {
+        let state: MpdLoopState = state
+            .parse()
+            .map_err(|e: anyhow::Error| fdo::Error::InvalidArgs(e.to_string()))?;
+        let commands = match state {
             MpdLoopState::None => ["repeat 0", "single 0"],
             MpdLoopState::Track => ["repeat 1", "single 1"],
             MpdLoopState::Playlist => ["repeat 1", "single 0"],
         };
         for cmd in commands {
-            self.mpdclient.lock().await.issue_command(cmd).await.ok();
+            if let Err(e) = self.mpdclient.issue_command(cmd).await {
+                error!("org.mpris.MediaPlayer2.Player.LoopStatus failed: {e}");
+                return Err(to_fdo_err(e).into());
+            }
         }
+        Ok(())
     }
 
     #[zbus(property, name = "Rate")]
@@ -173,24 +245,36 @@ impl PlayerInterface {
     }
 
     #[zbus(property, name = "Rate")]
-    async fn set_rate(&self, _rate: f64) {}
+    async fn set_rate(&self, rate: f64) -> zbus::Result<()> {
+        if rate != 1.0 {
+            return Err(
+                fdo::Error::NotSupported(format!("Playback rate {rate} is not supported")).into(),
+            );
+        }
+        Ok(())
+    }
 
     #[zbus(property, name = "Shuffle")]
     async fn shuffle(&self) -> bool {
-        self.mpd_state.read().await.random
+        self.mpdclient.get_status().random
     }
 
     #[zbus(property, name = "Shuffle")]
-    async fn set_shuffle(&self, shuffle: bool) {
+    async fn set_shuffle(&self, shuffle: bool) -> zbus::Result<()> {
         let cmd = if shuffle { "random 1" } else { "random 0" };
-        self.mpdclient.lock().await.issue_command(cmd).await.ok();
+        if let Err(e) = self.mpdclient.issue_command(cmd).await {
+            error!("org.mpris.MediaPlayer2.Player.Shuffle failed: {e}");
+            return Err(to_fdo_err(e).into());
+        }
+        Ok(())
     }
 
     #[zbus(property, name = "Metadata")]
     async fn metadata(&self) -> HashMap<String, Value<'_>> {
-        let state = self.mpd_state.read().await;
+        let state = self.mpdclient.get_status();
         let mut res = if let Some(metadata) = state.current_song.clone() {
-            match to_mpris_metadata(metadata) {
+            let music_dir = state.music_directory.as_deref();
+            match to_mpris_metadata(metadata, music_dir, &self.tags.load()) {
                 Ok(res) => res,
                 Err(e) => {
                     error!("org.mpris.MediaPlayer2.Player.Metadata failed: {}", e);
@@ -201,11 +285,32 @@ impl PlayerInterface {
             HashMap::new()
         };
 
-        let state = self.mpd_state.read().await;
+        let stickers = &state.stickers;
+        if let Some(rating) = stickers.rating {
+            let rating = f64::from(rating) / 10.0;
+            res.insert("xesam:userRating".to_owned(), Value::new(rating));
+        }
+        if let Some(count) = stickers.play_count {
+            let count = i32::try_from(count).unwrap_or(i32::MAX);
+            res.insert("xesam:useCount".to_owned(), Value::new(count));
+        }
+        if let Some(time) = stickers.last_played {
+            res.insert(
+                "xesam:lastUsed".to_owned(),
+                Value::new(unix_to_iso8601(time)),
+            );
+        }
+        if let Some(lyrics) = &state.lyrics {
+            res.insert("xesam:asText".to_owned(), Value::new(lyrics.text.clone()));
+        }
         if let Some(art) = &state.album_art {
             res.insert(
                 "mpris:artUrl".to_owned(),
-                Value::new(format!("file://{}", art.display())),
+                Value::new(
+                    art.url
+                        .clone()
+                        .unwrap_or_else(|| format!("file://{}", art.full.display())),
+                ),
             );
         }
         res
@@ -213,7 +318,7 @@ impl PlayerInterface {
 
     #[zbus(property, name = "Volume")]
     async fn volume(&self) -> f64 {
-        if let Some(vol) = self.mpd_state.read().await.volume {
+        if let Some(vol) = self.mpdclient.get_status().volume {
             vol as f64 / 100.0
         } else {
             100.0
@@ -221,32 +326,26 @@ impl PlayerInterface {
     }
 
     #[zbus(property, name = "Volume")]
-    async fn set_volume(&self, volume: f64) {
-        let mut volume = (volume * 100.0).floor();
-        if volume < 0.0 {
-            volume = 0.0;
+    async fn set_volume(&self, volume: f64) -> zbus::Result<()> {
+        if volume.is_nan() || volume > 1.0 {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Invalid volume {volume}, expect a value between 0.0 and 1.0"
+            ))
+            .into());
         }
-        let volume = volume as u64;
-        let cmd = format!("volume {volume}");
-        self.mpdclient.lock().await.issue_command(&cmd).await.ok();
+        // Negative values should be treated as 0.0, according to the spec
+        let volume = (volume * 100.0).round().max(0.0) as u64;
+        let cmd = format!("setvol {volume}");
+        if let Err(e) = self.mpdclient.issue_command(&cmd).await {
+            error!("org.mpris.MediaPlayer2.Player.Volume failed: {e}");
+            return Err(to_fdo_err(e).into());
+        }
+        Ok(())
     }
 
     #[zbus(property, name = "Position")]
     async fn position(&self) -> i64 {
-        use MpdPlaybackState::*;
-
-        self.mpdclient.lock().await.update_status().await.ok();
-        let elapsed = match &self.mpd_state.read().await.playback_state {
-            Playing(s) | Paused(s) => {
-                if let Some(elapsed) = s.elapsed {
-                    elapsed
-                } else {
-                    Duration::new(0, 0)
-                }
-            }
-            Stopped => Duration::new(0, 0),
-        };
-        elapsed.as_micros() as i64
+        self.mpdclient.get_status().position().as_micros() as i64
     }
 
     #[zbus(property, name = "MinimumRate")]
@@ -261,33 +360,33 @@ impl PlayerInterface {
 
     #[zbus(property, name = "CanGoNext")]
     async fn can_go_next(&self) -> bool {
-        let status = self.mpd_state.read().await;
-        status.next_song.is_some() || status.loop_state == MpdLoopState::Playlist
+        let status = self.mpdclient.get_status();
+        status.online && (status.next_song.is_some() || status.loop_state == MpdLoopState::Playlist)
     }
 
     #[zbus(property, name = "CanGoPrevious")]
     async fn can_go_previous(&self) -> bool {
-        true
+        self.mpdclient.get_status().online
     }
 
     #[zbus(property, name = "CanPlay")]
     async fn can_play(&self) -> bool {
-        let status = self.mpd_state.read().await;
+        let status = self.mpdclient.get_status();
         !matches!(status.playback_state, MpdPlaybackState::Stopped) || status.playlistlength != 0
     }
 
     #[zbus(property, name = "CanPause")]
     async fn can_pause(&self) -> bool {
-        true
+        self.mpdclient.get_status().online
     }
 
     #[zbus(property, name = "CanSeek")]
     async fn can_seek(&self) -> bool {
-        true
+        self.mpdclient.get_status().online
     }
 
     #[zbus(property, name = "CanControl")]
     async fn can_control(&self) -> bool {
-        true
+        self.mpdclient.get_status().online
     }
 }
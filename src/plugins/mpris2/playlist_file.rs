@@ -0,0 +1,366 @@
+/// Reading and writing M3U, PLS and XSPF playlist files
+use anyhow::{bail, Result};
+use quick_xml::{escape::escape, events::Event, Reader};
+use std::{collections::BTreeMap, fmt::Write, path::Path, str::FromStr, time::Duration};
+
+/// Playlist file formats we know
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    /// Guess the format from the extension of `path`
+    pub fn from_path(path: &str) -> Option<Self> {
+        let ext = Path::new(path).extension()?.to_str()?;
+        ext.to_ascii_lowercase().parse().ok()
+    }
+}
+
+impl FromStr for PlaylistFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "m3u" | "m3u8" => Ok(PlaylistFormat::M3u),
+            "pls" => Ok(PlaylistFormat::Pls),
+            "xspf" => Ok(PlaylistFormat::Xspf),
+            _ => Err(format!(
+                "unknown playlist format {s}, expect m3u, pls or xspf"
+            )),
+        }
+    }
+}
+
+/// A song in a playlist file
+#[derive(Clone, Debug, Default)]
+pub struct PlaylistEntry {
+    /// Path or URL, as written in the file
+    pub location: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+impl PlaylistEntry {
+    /// `Artist - Title`, as M3U and PLS files have it
+    fn full_title(&self) -> Option<String> {
+        let title = match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => format!("{artist} - {title}"),
+            (None, Some(title)) => title.clone(),
+            (Some(artist), None) => artist.clone(),
+            (None, None) => return None,
+        };
+        // Line breaks would end the entry early
+        Some(title.replace(['\r', '\n'], " "))
+    }
+}
+
+/// Read the entries of a playlist file
+pub fn parse(text: &str, format: PlaylistFormat) -> Result<Vec<PlaylistEntry>> {
+    let text = text.trim_start_matches('\u{feff}');
+    match format {
+        PlaylistFormat::M3u => Ok(parse_m3u(text)),
+        PlaylistFormat::Pls => Ok(parse_pls(text)),
+        PlaylistFormat::Xspf => parse_xspf(text),
+    }
+}
+
+/// Write `entries` out as a playlist file
+pub fn write(entries: &[PlaylistEntry], format: PlaylistFormat) -> String {
+    match format {
+        PlaylistFormat::M3u => write_m3u(entries),
+        PlaylistFormat::Pls => write_pls(entries),
+        PlaylistFormat::Xspf => write_xspf(entries),
+    }
+}
+
+fn parse_m3u(text: &str) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut next = PlaylistEntry::default();
+    for line in text.lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // `#EXTINF:123 key="value",Artist - Title`
+            let (head, title) = info.split_once(',').unwrap_or((info, ""));
+            let secs = head.split_whitespace().next().unwrap_or("");
+            // -1 for streams
+            next.duration = secs
+                .parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+            next.title = Some(title.trim().to_owned()).filter(|t| !t.is_empty());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            next.location = line.to_owned();
+            entries.push(std::mem::take(&mut next));
+        }
+    }
+    entries
+}
+
+fn parse_pls(text: &str) -> Vec<PlaylistEntry> {
+    let mut entries: BTreeMap<u32, PlaylistEntry> = BTreeMap::new();
+    for line in text.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+        let split = key
+            .find(|c: char| c.is_ascii_digit())
+            .map(|i| key.split_at(i));
+        let Some((name, n)) = split else {
+            continue;
+        };
+        let Ok(n) = n.parse() else {
+            continue;
+        };
+        let entry = entries.entry(n).or_default();
+        match name.to_ascii_lowercase().as_str() {
+            "file" => entry.location = value.to_owned(),
+            "title" if !value.is_empty() => entry.title = Some(value.to_owned()),
+            // -1 for streams
+            "length" => entry.duration = value.parse::<u64>().ok().map(Duration::from_secs),
+            _ => (),
+        }
+    }
+    entries
+        .into_values()
+        .filter(|entry| !entry.location.is_empty())
+        .collect()
+}
+
+fn parse_xspf(text: &str) -> Result<Vec<PlaylistEntry>> {
+    let mut reader = Reader::from_str(text);
+    reader.config_mut().trim_text(true);
+
+    let mut entries = Vec::new();
+    let mut track: Option<PlaylistEntry> = None;
+    // Element we're in, if it's one we want the text of
+    let mut field = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"track" => track = Some(PlaylistEntry::default()),
+                name @ (b"location" | b"title" | b"creator" | b"duration") if track.is_some() => {
+                    field = Some(name.to_vec())
+                }
+                _ => field = None,
+            },
+            Event::Text(t) => {
+                let (Some(track), Some(name)) = (&mut track, &field) else {
+                    continue;
+                };
+                let text = t.unescape()?.into_owned();
+                match name.as_slice() {
+                    // Only the first location is used
+                    b"location" if track.location.is_empty() => track.location = text,
+                    b"title" => track.title = Some(text),
+                    b"creator" => track.artist = Some(text),
+                    // In milliseconds
+                    b"duration" => track.duration = text.parse().ok().map(Duration::from_millis),
+                    _ => (),
+                }
+            }
+            Event::End(e) => {
+                field = None;
+                if e.local_name().as_ref() == b"track" {
+                    entries.extend(track.take().filter(|t| !t.location.is_empty()));
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    if entries.is_empty() && !text.contains("<playlist") {
+        bail!("not a XSPF playlist");
+    }
+    Ok(entries)
+}
+
+fn write_m3u(entries: &[PlaylistEntry]) -> String {
+    let mut res = String::from("#EXTM3U\n");
+    for entry in entries {
+        let secs = entry.duration.map_or(-1, |d| d.as_secs() as i64);
+        let title = entry.full_title().unwrap_or_default();
+        let _ = writeln!(res, "#EXTINF:{secs},{title}\n{}", entry.location);
+    }
+    res
+}
+
+fn write_pls(entries: &[PlaylistEntry]) -> String {
+    let mut res = String::from("[playlist]\n");
+    for (i, entry) in entries.iter().enumerate() {
+        let n = i + 1;
+        let _ = writeln!(res, "File{n}={}", entry.location);
+        if let Some(title) = entry.full_title() {
+            let _ = writeln!(res, "Title{n}={title}");
+        }
+        let secs = entry.duration.map_or(-1, |d| d.as_secs() as i64);
+        let _ = writeln!(res, "Length{n}={secs}");
+    }
+    let _ = write!(res, "NumberOfEntries={}\nVersion=2\n", entries.len());
+    res
+}
+
+fn write_xspf(entries: &[PlaylistEntry]) -> String {
+    let mut res = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n",
+    );
+    for entry in entries {
+        res += "    <track>\n";
+        let _ = writeln!(
+            res,
+            "      <location>{}</location>",
+            escape(entry.location.as_str())
+        );
+        if let Some(artist) = &entry.artist {
+            let _ = writeln!(res, "      <creator>{}</creator>", escape(artist.as_str()));
+        }
+        if let Some(title) = &entry.title {
+            let _ = writeln!(res, "      <title>{}</title>", escape(title.as_str()));
+        }
+        if let Some(duration) = entry.duration {
+            let _ = writeln!(res, "      <duration>{}</duration>", duration.as_millis());
+        }
+        res += "    </track>\n";
+    }
+    res += "  </trackList>\n</playlist>\n";
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<PlaylistEntry> {
+        vec![
+            PlaylistEntry {
+                location: "Album/01 - One & Two.flac".to_owned(),
+                artist: Some("Artist".to_owned()),
+                title: Some("One <&> Two".to_owned()),
+                duration: Some(Duration::from_secs(185)),
+            },
+            PlaylistEntry {
+                location: "http://radio.example/stream?a=1&b=2".to_owned(),
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn summary(entries: &[PlaylistEntry]) -> Vec<(String, Option<String>, Option<u64>)> {
+        entries
+            .iter()
+            .map(|e| {
+                let duration = e.duration.map(|d| d.as_secs());
+                (e.location.clone(), e.full_title(), duration)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in [
+            PlaylistFormat::M3u,
+            PlaylistFormat::Pls,
+            PlaylistFormat::Xspf,
+        ] {
+            let text = write(&entries(), format);
+            let parsed = parse(&text, format).unwrap();
+            assert_eq!(summary(&parsed), summary(&entries()), "{format:?}:\n{text}");
+        }
+        // XSPF keeps artist and title apart
+        let parsed = parse(
+            &write(&entries(), PlaylistFormat::Xspf),
+            PlaylistFormat::Xspf,
+        );
+        let first = &parsed.unwrap()[0];
+        assert_eq!(first.artist.as_deref(), Some("Artist"));
+        assert_eq!(first.title.as_deref(), Some("One <&> Two"));
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            PlaylistFormat::from_path("/a/b.M3U8"),
+            Some(PlaylistFormat::M3u)
+        );
+        assert_eq!(
+            PlaylistFormat::from_path("b.pls"),
+            Some(PlaylistFormat::Pls)
+        );
+        assert_eq!(
+            PlaylistFormat::from_path("b.xspf"),
+            Some(PlaylistFormat::Xspf)
+        );
+        assert_eq!(PlaylistFormat::from_path("b.txt"), None);
+        assert_eq!(PlaylistFormat::from_path("m3u"), None);
+    }
+
+    #[test]
+    fn m3u() {
+        let text =
+            "\u{feff}#EXTM3U\n\n# comment\n#EXTINF:12.5 tvg-id=\"x\",A - B\r\na.mp3\r\nb.mp3\n";
+        let parsed = parse(text, PlaylistFormat::M3u).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].location, "a.mp3");
+        assert_eq!(parsed[0].title.as_deref(), Some("A - B"));
+        assert_eq!(parsed[0].duration, Some(Duration::from_millis(12500)));
+        // EXTINF only applies to the next entry
+        assert_eq!(parsed[1].title, None);
+        assert_eq!(parsed[1].duration, None);
+    }
+
+    #[test]
+    fn pls() {
+        let text = "[playlist]\nFile2=b.mp3\nfile1 = a.mp3\nTitle1=A\nLength1=-1\nTitle3=Orphan\nNumberOfEntries=2\n";
+        let parsed = parse(text, PlaylistFormat::Pls).unwrap();
+        let locations: Vec<_> = parsed.iter().map(|e| e.location.as_str()).collect();
+        assert_eq!(locations, ["a.mp3", "b.mp3"]);
+        assert_eq!(parsed[0].title.as_deref(), Some("A"));
+        assert_eq!(parsed[0].duration, None);
+    }
+
+    #[test]
+    fn xspf() {
+        let text = r#"<?xml version="1.0"?>
+            <playlist xmlns="http://xspf.org/ns/0/"><title>List</title><trackList>
+            <track><location>a.flac</location><location>ignored.flac</location>
+            <duration>1500</duration></track>
+            <track><title>No location</title></track>
+            </trackList></playlist>"#;
+        let parsed = parse(text, PlaylistFormat::Xspf).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].location, "a.flac");
+        assert_eq!(parsed[0].title, None);
+        assert_eq!(parsed[0].duration, Some(Duration::from_millis(1500)));
+
+        assert!(parse("<html></html>", PlaylistFormat::Xspf).is_err());
+        assert!(parse("<playlist><trackList>", PlaylistFormat::Xspf).is_ok());
+    }
+
+    #[test]
+    fn out_of_range_durations() {
+        let text = "#EXTINF:1e30,x\na.mp3\n#EXTINF:inf,y\nb.mp3\n#EXTINF:NaN,z\nc.mp3\n";
+        let parsed = parse(text, PlaylistFormat::M3u).unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert!(parsed.iter().all(|e| e.duration.is_none()));
+
+        let text = "[playlist]\nFile1=a.mp3\nLength1=99999999999999999999999\n";
+        assert_eq!(parse(text, PlaylistFormat::Pls).unwrap()[0].duration, None);
+    }
+
+    #[test]
+    fn line_breaks_in_titles() {
+        let entry = PlaylistEntry {
+            location: "a.mp3".to_owned(),
+            title: Some("Two\nlines".to_owned()),
+            ..Default::default()
+        };
+        let text = write(&[entry], PlaylistFormat::M3u);
+        let parsed = parse(&text, PlaylistFormat::M3u).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].title.as_deref(), Some("Two lines"));
+    }
+}
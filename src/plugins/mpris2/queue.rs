@@ -0,0 +1,441 @@
+/// Queue management MPRIS has no place for (me.szclsya.mpdris2.Queue)
+use super::{
+    playlist_file::{self, PlaylistEntry, PlaylistFormat},
+    utils::*,
+};
+use crate::mpd::{quote, types, MpdError, MpdStateServer};
+
+use log::{debug, error, warn};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::fs;
+use zbus::{fdo, interface};
+use zvariant::{ObjectPath, OwnedObjectPath};
+
+/// How many songs are added to the queue with one command list
+const ADD_BATCH: usize = 500;
+
+pub struct QueueInterface {
+    mpdclient: MpdStateServer,
+}
+
+impl QueueInterface {
+    pub fn new(mpdclient: MpdStateServer) -> Self {
+        QueueInterface { mpdclient }
+    }
+}
+
+#[interface(name = "me.szclsya.mpdris2.Queue")]
+impl QueueInterface {
+    /// Move a song to another position in the queue
+    #[zbus(name = "MoveTrack")]
+    async fn move_track(&self, track: ObjectPath<'_>, position: u32) -> fdo::Result<()> {
+        let id = track_id(&track)?;
+        self.command("MoveTrack", &format!("moveid {id} {position}"))
+            .await
+    }
+
+    /// Songs with a higher priority (0 to 255) are played first in random mode
+    #[zbus(name = "SetPriority")]
+    async fn set_priority(&self, track: ObjectPath<'_>, priority: u8) -> fdo::Result<()> {
+        let id = track_id(&track)?;
+        self.command("SetPriority", &format!("prioid {priority} {id}"))
+            .await
+    }
+
+    /// Add a song right after the current one. Returns its track id.
+    #[zbus(name = "PlayNext")]
+    async fn play_next(&self, uri: &str) -> fdo::Result<OwnedObjectPath> {
+        let state = self.mpdclient.get_status();
+        let cmd = play_next_command(uri, &state).map_err(|e| {
+            error!("me.szclsya.mpdris2.Queue.PlayNext failed: {e}");
+            e
+        })?;
+        let resp = self.mpdclient.issue_command(&cmd).await.map_err(|e| {
+            error!("me.szclsya.mpdris2.Queue.PlayNext failed: {e}");
+            to_fdo_err(e)
+        })?;
+        let id = resp
+            .fields
+            .iter()
+            .find(|(name, _)| name == "Id")
+            .map(|(_, id)| id.as_str())
+            .unwrap_or_default();
+        Ok(id_to_object_path(id).into())
+    }
+
+    /// Replace the queue with the album of the current song in track order,
+    /// without interrupting the current song
+    #[zbus(name = "PlayAlbumOfCurrent")]
+    async fn play_album_of_current(&self) -> fdo::Result<()> {
+        self.play_album_of_current_inner().await.map_err(|e| {
+            error!("me.szclsya.mpdris2.Queue.PlayAlbumOfCurrent failed: {e}");
+            e
+        })
+    }
+
+    /// Shuffle the songs from `start` up to (not including) `end`
+    #[zbus(name = "ShuffleRange")]
+    async fn shuffle_range(&self, start: u32, end: u32) -> fdo::Result<()> {
+        if start >= end {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Empty range {start}..{end}"
+            )));
+        }
+        self.command("ShuffleRange", &format!("shuffle {start}:{end}"))
+            .await
+    }
+
+    /// Remove songs that are in the queue more than once, keeping the current
+    /// one or else the first. Returns how many were removed.
+    #[zbus(name = "RemoveDuplicates")]
+    async fn remove_duplicates(&self) -> fdo::Result<u32> {
+        let state = self.mpdclient.get_status();
+        let current = state.song.map(|(_, id)| id.to_string());
+        let id_of = |song: &HashMap<String, Vec<String>>| song.get("Id").map(|id| id[0].clone());
+
+        let mut keep: HashMap<&str, String> = HashMap::new();
+        let mut remove = Vec::new();
+        for song in state.queue.iter() {
+            let (Some(file), Some(id)) = (song.get("file"), id_of(song)) else {
+                continue;
+            };
+            match keep.get_mut(file[0].as_str()) {
+                Some(kept) if Some(&id) == current.as_ref() => {
+                    remove.push(std::mem::replace(kept, id))
+                }
+                Some(_) => remove.push(id),
+                None => {
+                    keep.insert(&file[0], id);
+                }
+            }
+        }
+        for id in &remove {
+            self.command("RemoveDuplicates", &format!("deleteid {id}"))
+                .await?;
+        }
+        Ok(remove.len() as u32)
+    }
+
+    /// Remove every song from the queue
+    #[zbus(name = "Clear")]
+    async fn clear(&self) -> fdo::Result<()> {
+        self.command("Clear", "clear").await
+    }
+
+    /// Length of all songs in the queue, in microseconds
+    #[zbus(property, name = "TotalTime")]
+    async fn total_time(&self) -> i64 {
+        let state = self.mpdclient.get_status();
+        let total: Duration = state.queue.iter().filter_map(song_duration).sum();
+        total.as_micros() as i64
+    }
+
+    /// What's left to play of the current song and the ones after it, in
+    /// microseconds. Only signalled when it jumps, not while playing.
+    #[zbus(property, name = "RemainingTime")]
+    async fn remaining_time(&self) -> i64 {
+        let state = self.mpdclient.get_status();
+        let Some((pos, _)) = state.song else {
+            return self.total_time().await;
+        };
+        let rest: Duration = (state.queue.iter().skip(pos as usize))
+            .filter_map(song_duration)
+            .sum();
+        rest.saturating_sub(state.position()).as_micros() as i64
+    }
+
+    /// Add the songs of a M3U, PLS or XSPF file to the end of the queue.
+    /// Returns how many were added.
+    #[zbus(name = "ImportPlaylist")]
+    async fn import_playlist(&self, path: &str) -> fdo::Result<u32> {
+        let (_, added) = import(&self.mpdclient, path).await.map_err(|e| {
+            error!("me.szclsya.mpdris2.Queue.ImportPlaylist failed: {e}");
+            e
+        })?;
+        Ok(added as u32)
+    }
+
+    /// Write the queue to a playlist file. The format is `m3u`, `pls` or
+    /// `xspf`, or guessed from the file name if empty.
+    #[zbus(name = "ExportPlaylist")]
+    async fn export_playlist(&self, path: &str, format: &str) -> fdo::Result<()> {
+        export(&self.mpdclient, path, format).await.map_err(|e| {
+            error!("me.szclsya.mpdris2.Queue.ExportPlaylist failed: {e}");
+            e
+        })
+    }
+}
+
+impl QueueInterface {
+    async fn command(&self, method: &str, cmd: &str) -> fdo::Result<()> {
+        match self.mpdclient.issue_command(cmd).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("me.szclsya.mpdris2.Queue.{method} failed: {e}");
+                Err(to_fdo_err(e))
+            }
+        }
+    }
+
+    async fn play_album_of_current_inner(&self) -> fdo::Result<()> {
+        let state = self.mpdclient.get_status();
+        let (Some((pos, id)), Some(song)) = (state.song, &state.current_song) else {
+            return Err(fdo::Error::Failed("Nothing is playing".to_owned()));
+        };
+        let first = |tag: &str| song.get(tag).map(|v| v[0].as_str());
+        let (Some(file), Some(album)) = (first("file"), first("Album")) else {
+            return Err(fdo::Error::Failed(
+                "The current song has no album".to_owned(),
+            ));
+        };
+        if file.contains("://") {
+            return Err(fdo::Error::NotSupported(
+                "Streams have no albums".to_owned(),
+            ));
+        }
+        // Same album in the same directory, like album art
+        let dir = file.rsplit_once('/').map_or("", |(dir, _)| dir);
+        let filter = format!(
+            "((Album == {}) AND (base {}))",
+            quote_arg(album)?,
+            quote_arg(dir)?
+        );
+        let mut songs = self
+            .mpdclient
+            .issue_command(&format!("find {}", quote_arg(&filter)?))
+            .await
+            .map_err(to_fdo_err)?
+            .songs();
+        songs.sort_by_key(|song| {
+            let num = |tag: &str| {
+                song.get(tag)
+                    .and_then(|v| leading_number::<u32>(&v[0]))
+                    .unwrap_or(0)
+            };
+            // Albums with a single disc often don't say so
+            (num("Disc").max(1), num("Track"))
+        });
+        let files: Vec<&str> = songs
+            .iter()
+            .filter_map(|song| song.get("file").map(|f| f[0].as_str()))
+            .collect();
+        let Some(current) = files.iter().position(|f| *f == file) else {
+            return Err(fdo::Error::Failed(format!("{file} is not in the database")));
+        };
+
+        // Keep the current song playing, and put the album around it
+        let mut cmds = Vec::new();
+        if pos > 0 {
+            cmds.push(format!("delete 0:{pos}"));
+        }
+        cmds.push("delete 1:".to_owned());
+        for (i, f) in files[..current].iter().enumerate() {
+            cmds.push(format!("add {} {i}", quote_arg(f)?));
+        }
+        for f in &files[current + 1..] {
+            cmds.push(format!("add {}", quote_arg(f)?));
+        }
+        debug!("Playing the album of song {id}");
+        for cmd in cmds {
+            self.command("PlayAlbumOfCurrent", &cmd).await?;
+        }
+        Ok(())
+    }
+}
+
+/// `addid` command that puts `uri` right after the current song
+fn play_next_command(uri: &str, state: &types::MpdState) -> fdo::Result<String> {
+    let mpd_uri = from_url(uri, state).map_err(fdo::Error::NotSupported)?;
+    // Without a current song, next is the start of the queue
+    let pos = if state.song.is_some() { "+0" } else { "0" };
+    Ok(format!("addid {} {pos}", quote_arg(&mpd_uri)?))
+}
+
+fn track_id(track: &ObjectPath) -> fdo::Result<u64> {
+    object_path_to_id(track)
+        .ok_or_else(|| fdo::Error::InvalidArgs(format!("Invalid track id: {track}")))
+}
+
+/// Add the songs of a playlist file at `path`, a local path or `file://`
+/// URL, to the end of the queue. Returns where they start and how many were
+/// added. Songs MPD can't find are skipped.
+pub async fn import(mpd: &MpdStateServer, path: &str) -> fdo::Result<(u64, usize)> {
+    let path = local_path(path)?;
+    let format = format_of(&path)?;
+    let data = fs::read(&path)
+        .await
+        .map_err(|e| fdo::Error::IOError(format!("Can't read {}: {e}", path.display())))?;
+    let entries = playlist_file::parse(&String::from_utf8_lossy(&data), format)
+        .map_err(|e| fdo::Error::InvalidArgs(format!("Can't parse {}: {e}", path.display())))?;
+
+    let state = mpd.get_status();
+    let base = path.parent().unwrap_or(Path::new("/"));
+    let mut songs = Vec::new();
+    for entry in entries {
+        let uri = if entry.location.contains("://") {
+            from_url(&entry.location, &state)
+        } else {
+            // Relative to the playlist file
+            let location = base.join(&entry.location);
+            to_mpd_path(&location, state.music_directory.as_deref())
+        };
+        let uri = uri.and_then(|uri| quote(&uri).map_err(|e| e.to_string()));
+        match uri {
+            Ok(uri) => songs.push((uri, entry.location)),
+            Err(e) => warn!("Skipping {} in {}: {e}", entry.location, path.display()),
+        }
+    }
+
+    let pos = queue_end(mpd).await.map_err(to_fdo_err)?;
+    let mut added = 0;
+    for batch in songs.chunks(ADD_BATCH) {
+        added += add_all(mpd, batch, &path).await?;
+    }
+    Ok((pos, added))
+}
+
+/// Add `(quoted uri, location)` songs to the queue in command lists, so that big
+/// playlists don't take a round trip per song. Songs MPD refuses are
+/// skipped. Returns how many were added.
+async fn add_all(
+    mpd: &MpdStateServer,
+    songs: &[(String, String)],
+    path: &Path,
+) -> fdo::Result<usize> {
+    let mut added = 0;
+    let mut rest = songs;
+    while !rest.is_empty() {
+        let mut cmd = String::from("command_list_begin\n");
+        for (uri, _) in rest {
+            cmd += &format!("add {uri}\n");
+        }
+        cmd += "command_list_end";
+        let e = match mpd.issue_command(&cmd).await {
+            Ok(_) => return Ok(added + rest.len()),
+            Err(e) => e,
+        };
+        // MPD stops at the first failing command, go on after it
+        let Some(failed) = e.downcast_ref::<MpdError>().map(MpdError::command_list_no) else {
+            return Err(to_fdo_err(e));
+        };
+        let failed = failed.min(rest.len() - 1);
+        warn!("Skipping {} in {}: {e}", rest[failed].1, path.display());
+        added += failed;
+        rest = &rest[failed + 1..];
+    }
+    Ok(added)
+}
+
+async fn export(mpd: &MpdStateServer, path: &str, format: &str) -> fdo::Result<()> {
+    let path = local_path(path)?;
+    let format = if format.is_empty() {
+        format_of(&path)?
+    } else {
+        format.parse().map_err(fdo::Error::InvalidArgs)?
+    };
+    let songs = mpd
+        .issue_command("playlistinfo")
+        .await
+        .map_err(to_fdo_err)?
+        .songs();
+
+    let music_dir = mpd.get_status().music_directory.clone();
+    let entries: Vec<_> = songs
+        .into_iter()
+        .map(types::with_stream_tags)
+        .filter_map(|song| to_entry(song, music_dir.as_deref(), format))
+        .collect();
+    fs::write(&path, playlist_file::write(&entries, format))
+        .await
+        .map_err(|e| fdo::Error::IOError(format!("Can't write {}: {e}", path.display())))
+}
+
+/// Where a song is for other players: an absolute path if we know the music
+/// directory (a URL for XSPF), the stream URL, or else the path MPD knows
+fn to_entry(
+    mut song: HashMap<String, Vec<String>>,
+    music_dir: Option<&Path>,
+    format: PlaylistFormat,
+) -> Option<PlaylistEntry> {
+    let uri = song.remove("file")?.into_iter().next()?;
+    let location = match music_dir {
+        Some(_) if format == PlaylistFormat::Xspf => to_url(&uri, music_dir)?,
+        Some(dir) if !uri.contains("://") => dir.join(&uri).display().to_string(),
+        _ => uri,
+    };
+    let duration = song_duration(&song);
+    let mut first = |tag: &str| song.remove(tag).and_then(|v| v.into_iter().next());
+    Some(PlaylistEntry {
+        location,
+        artist: first("Artist"),
+        title: first("Title"),
+        duration,
+    })
+}
+
+fn local_path(path: &str) -> fdo::Result<PathBuf> {
+    let path = if path.contains("://") {
+        file_url_to_path(path).map_err(fdo::Error::InvalidArgs)?
+    } else {
+        PathBuf::from(path)
+    };
+    if !path.is_absolute() {
+        return Err(fdo::Error::InvalidArgs(format!(
+            "{} is not an absolute path",
+            path.display()
+        )));
+    }
+    Ok(path)
+}
+
+fn format_of(path: &Path) -> fdo::Result<PlaylistFormat> {
+    path.to_str()
+        .and_then(PlaylistFormat::from_path)
+        .ok_or_else(|| {
+            fdo::Error::InvalidArgs(format!(
+                "Can't tell the playlist format of {}",
+                path.display()
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn play_next_commands() {
+        let mut state = types::MpdState {
+            music_directory: Some(PathBuf::from("/music")),
+            uri_schemes: Arc::new(vec!["http".to_owned()]),
+            ..types::MpdState::offline()
+        };
+        let cmd = |state: &types::MpdState, uri| play_next_command(uri, state);
+        assert_eq!(
+            cmd(&state, "file:///music/a%20b.flac").unwrap(),
+            r#"addid "a b.flac" 0"#
+        );
+        state.song = Some((3, 7));
+        assert_eq!(
+            cmd(&state, "http://radio/stream").unwrap(),
+            r#"addid "http://radio/stream" +0"#
+        );
+        assert!(matches!(
+            cmd(&state, "ftp://host/a.flac"),
+            Err(fdo::Error::NotSupported(_))
+        ));
+        // A second command must not sneak in
+        for uri in [
+            "file:///music/a.flac%0Aclear",
+            "file:///music/a.flac%0D",
+            "http://radio/stream\nclear",
+        ] {
+            assert!(matches!(cmd(&state, uri), Err(fdo::Error::InvalidArgs(_))));
+        }
+    }
+}
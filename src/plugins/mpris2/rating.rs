@@ -0,0 +1,59 @@
+/// Rating songs with MPD stickers (me.szclsya.mpdris2.Rating)
+use super::utils::*;
+use crate::mpd::MpdStateServer;
+
+use log::error;
+use zbus::{fdo, interface};
+
+pub struct RatingInterface {
+    mpdclient: MpdStateServer,
+}
+
+impl RatingInterface {
+    pub fn new(mpdclient: MpdStateServer) -> Self {
+        RatingInterface { mpdclient }
+    }
+}
+
+#[interface(name = "me.szclsya.mpdris2.Rating")]
+impl RatingInterface {
+    /// Rate the current song from 0.0 to 1.0, like `xesam:userRating`. It's
+    /// saved as the `rating` sticker from 0 to 10, and 0 removes it.
+    #[zbus(name = "RateCurrent")]
+    async fn rate_current(&self, rating: f64) -> fdo::Result<()> {
+        if !(0.0..=1.0).contains(&rating) {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "rating {rating} isn't between 0 and 1"
+            )));
+        }
+        let state = self.mpdclient.get_status();
+        let file = state
+            .current_song
+            .as_ref()
+            .and_then(|song| song.get("file"));
+        let Some(file) = file.map(|f| &f[0]) else {
+            return Err(fdo::Error::Failed("Nothing is playing".to_owned()));
+        };
+        if file.contains("://") {
+            return Err(fdo::Error::NotSupported(
+                "Only songs in the database can be rated".to_owned(),
+            ));
+        }
+
+        let rating = (rating * 10.0).round() as u8;
+        let cmd = if rating == 0 {
+            format!("sticker delete song {} rating", quote_arg(file)?)
+        } else {
+            format!("sticker set song {} rating {rating}", quote_arg(file)?)
+        };
+        if let Err(e) = self.mpdclient.issue_command(&cmd).await {
+            // Deleting a rating that isn't there is fine
+            if rating == 0 && state.stickers.rating.is_none() {
+                return Ok(());
+            }
+            error!("me.szclsya.mpdris2.Rating.RateCurrent failed: {e}");
+            return Err(to_fdo_err(e));
+        }
+        Ok(())
+    }
+}
@@ -1,59 +1,78 @@
 /// The root interface (org.mpris.MediaPlayer2) implementation
+use crate::mpd::MpdStateServer;
+
 use zbus::interface;
 
-#[derive(Default)]
-pub struct RootInterface {}
+pub struct RootInterface {
+    mpdclient: MpdStateServer,
+    identity: String,
+}
+
+impl RootInterface {
+    pub fn new(mpdclient: MpdStateServer, identity: String) -> Self {
+        RootInterface {
+            mpdclient,
+            identity,
+        }
+    }
+}
 
 #[interface(name = "org.mpris.MediaPlayer2")]
 impl RootInterface {
-    #[dbus_interface(name = "Raise")]
+    #[zbus(name = "Raise")]
     async fn raise(&self) {
         // mpd runs in daemon, do nothing
     }
 
-    #[dbus_interface(name = "Quit")]
+    #[zbus(name = "Quit")]
     async fn quit(&self) {
         // mpd runs in daemon, do nothing
     }
 
-    #[dbus_interface(property, name = "CanQuit")]
+    #[zbus(property, name = "CanQuit")]
     async fn can_quit(&self) -> bool {
         false
     }
 
-    #[dbus_interface(property, name = "CanSetFullscreen")]
+    #[zbus(property, name = "CanSetFullscreen")]
     async fn can_set_fullscreen(&self) -> bool {
         false
     }
 
-    #[dbus_interface(property, name = "CanRaise")]
+    #[zbus(property, name = "CanRaise")]
     async fn can_raise(&self) -> bool {
         false
     }
 
-    #[dbus_interface(property, name = "HasTrackList")]
+    #[zbus(property, name = "HasTrackList")]
     async fn has_track_list(&self) -> bool {
         // TODO: Implement TrackList interface and change this to true
         false
     }
 
-    #[dbus_interface(property, name = "Identity")]
+    #[zbus(property, name = "Identity")]
     async fn identity(&self) -> &str {
-        "Music Player Daemon (mpdris2-rs)"
+        &self.identity
     }
 
-    #[dbus_interface(property, name = "DesktopEntry")]
+    #[zbus(property, name = "DesktopEntry")]
     async fn desktop_entry(&self) -> &str {
         "mpdris2-rs"
     }
 
-    #[dbus_interface(property, name = "SupportedUriSchemes")]
-    async fn supported_uri_schemes(&self) -> &[&str] {
-        &[]
+    #[zbus(property, name = "SupportedUriSchemes")]
+    async fn supported_uri_schemes(&self) -> Vec<String> {
+        let state = self.mpdclient.get_status();
+        let mut schemes = Vec::clone(&state.uri_schemes);
+        // Files in the music directory are turned into paths MPD knows
+        if state.music_directory.is_some() && !schemes.iter().any(|s| s == "file") {
+            schemes.push("file".to_owned());
+        }
+        schemes
     }
 
-    #[dbus_interface(property, name = "SupportedMimeTypes")]
-    async fn supported_mime_types(&self) -> &[&str] {
-        &[]
+    #[zbus(property, name = "SupportedMimeTypes")]
+    async fn supported_mime_types(&self) -> Vec<String> {
+        Vec::clone(&self.mpdclient.get_status().mime_types)
     }
 }
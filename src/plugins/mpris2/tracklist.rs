@@ -1,20 +1,21 @@
 use super::utils::*;
 /// `TrackList` interface (org.mpris.MediaPlayer2.TrackList) implementation
-use crate::mpd::MpdStateServer;
+use crate::mpd::{types::MpdState, MpdStateServer};
 
+use arc_swap::ArcSwap;
 use log::error;
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
 use zbus::{interface, SignalContext};
 use zvariant::{ObjectPath, Value};
 
 pub struct TracklistInterface {
-    mpdclient: Arc<Mutex<MpdStateServer>>,
+    mpdclient: MpdStateServer,
+    tags: Arc<ArcSwap<Vec<TagMapping>>>,
 }
 
 impl TracklistInterface {
-    pub fn new(mpdclient: Arc<Mutex<MpdStateServer>>) -> Self {
-        TracklistInterface { mpdclient }
+    pub fn new(mpdclient: MpdStateServer, tags: Arc<ArcSwap<Vec<TagMapping>>>) -> Self {
+        TracklistInterface { mpdclient, tags }
     }
 }
 
@@ -27,7 +28,7 @@ impl<'a> TracklistInterface {
     ) -> zbus::fdo::Result<Vec<HashMap<String, Value<'a>>>> {
         let ids: Vec<Value<'_>> = tracks.into_iter().map(Value::new).collect();
 
-        let metadatas = get_current_playlist(self.mpdclient.clone()).await?;
+        let metadatas = get_current_playlist(&self.mpdclient.get_status(), &self.tags.load())?;
         let metadatas = metadatas
             .into_iter()
             .filter(|metadatas| {
@@ -43,13 +44,23 @@ impl<'a> TracklistInterface {
     }
 
     #[zbus(name = "AddTrack")]
-    async fn add_track(&self, _uri: String, _after: ObjectPath<'_>, _set_as_current: bool) {
-        // We don't do that here.jpg
+    async fn add_track(
+        &self,
+        _uri: String,
+        _after: ObjectPath<'_>,
+        _set_as_current: bool,
+    ) -> zbus::fdo::Result<()> {
+        // CanEditTracks is false, the queue is edited through me.szclsya.mpdris2.Queue
+        Err(zbus::fdo::Error::NotSupported(
+            "Tracks can't be added through the track list".to_owned(),
+        ))
     }
 
     #[zbus(name = "RemoveTrack")]
-    async fn remove_track(&self, _track: ObjectPath<'_>) {
-        // We don't do that here either
+    async fn remove_track(&self, _track: ObjectPath<'_>) -> zbus::fdo::Result<()> {
+        Err(zbus::fdo::Error::NotSupported(
+            "Tracks can't be removed through the track list".to_owned(),
+        ))
     }
 
     #[zbus(name = "GoTo")]
@@ -58,24 +69,19 @@ impl<'a> TracklistInterface {
         #[zbus(signal_context)] ctxt: SignalContext<'_>,
         track: ObjectPath<'_>,
     ) -> zbus::fdo::Result<()> {
-        let id = if let Some(id) = object_path_to_id(&track) {
-            id
-        } else {
-            return Ok(());
-        };
+        let id = object_path_to_id(&track)
+            .ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("Invalid track id: {track}")))?;
 
         let cmd = format!("playid {}", id);
-        match self.mpdclient.lock().await.issue_command(&cmd).await {
-            Ok(_resp) => {
-                let mut new_metadata = self.get_track_metadata(vec![track.clone()]).await?;
-                let new_metadata = new_metadata.remove(0);
-                if !new_metadata.is_empty() {
-                    TracklistInterface::track_metadata_changed(&ctxt, track, new_metadata).await?;
-                }
-            }
-            Err(e) => {
-                error!("org.mpris.MediaPlayer2.TrackList.GoTo failed: {e}");
-            }
+        if let Err(e) = self.mpdclient.issue_command(&cmd).await {
+            error!("org.mpris.MediaPlayer2.TrackList.GoTo failed: {e}");
+            return Err(to_fdo_err(e));
+        }
+
+        let mut new_metadata = self.get_track_metadata(vec![track.clone()]).await?;
+        if !new_metadata.is_empty() {
+            let new_metadata = new_metadata.remove(0);
+            TracklistInterface::track_metadata_changed(&ctxt, track, new_metadata).await?;
         }
         Ok(())
     }
@@ -108,24 +114,13 @@ impl<'a> TracklistInterface {
 
     #[zbus(property, name = "Tracks")]
     async fn tracks(&self) -> Vec<ObjectPath<'_>> {
-        let client = self.mpdclient.lock().await;
-        let resp = match client.issue_command("playlistinfo").await {
-            Ok(resp) => resp,
-            Err(e) => {
-                error!("org.mpris.MediaPlayer2.Tracks failed: {e}");
-                return Vec::new();
-            }
-        };
-
-        let mut ids = Vec::new();
-        for (name, value) in resp.fields {
-            if name == "Id" {
-                let id = id_to_object_path(value);
-                ids.push(id);
-            }
-        }
-
-        ids
+        self.mpdclient
+            .get_status()
+            .queue
+            .iter()
+            .filter_map(|song| song.get("Id"))
+            .map(|id| id_to_object_path(&id[0]))
+            .collect()
     }
 
     #[zbus(property, name = "CanEditTracks")]
@@ -134,49 +129,29 @@ impl<'a> TracklistInterface {
     }
 }
 
-pub async fn get_current_playlist<'a>(
-    client: Arc<Mutex<MpdStateServer>>,
+pub fn get_current_playlist<'a>(
+    state: &MpdState,
+    tags: &[TagMapping],
 ) -> zbus::fdo::Result<Vec<HashMap<std::string::String, zvariant::Value<'a>>>> {
-    let res = client
-        .lock()
-        .await
-        .issue_command("playlistinfo")
-        .await
-        .map_err(to_fdo_err)?;
-
-    let mut metadatas = Vec::new();
-
-    let mut buf = HashMap::new();
-    for (name, value) in res.fields {
-        buf.entry(name.clone())
-            .or_insert_with(|| vec![value.clone()])
-            .push(value.clone());
-        if name == "Id" {
-            let mut new_buf = HashMap::new();
-            std::mem::swap(&mut buf, &mut new_buf);
-            let new_metadata = to_mpris_metadata(new_buf).map_err(to_fdo_err)?;
-            metadatas.push(new_metadata);
-        }
-    }
-
-    Ok(metadatas)
+    let music_dir = state.music_directory.as_deref();
+    state
+        .queue
+        .iter()
+        .map(|song| to_mpris_metadata(song.clone(), music_dir, tags).map_err(to_fdo_err))
+        .collect()
 }
 
 pub fn extract_ids_from_metadata<'a>(
     i: &HashMap<String, Value<'_>>,
 ) -> zbus::fdo::Result<ObjectPath<'a>> {
     let path = i
-        .get("mpris::trackid")
-        .ok_or_else(|| zbus::fdo::Error::Failed("mpris::trackid doesn't exist".to_string()))?;
+        .get("mpris:trackid")
+        .ok_or_else(|| zbus::fdo::Error::Failed("mpris:trackid doesn't exist".to_string()))?;
     if let Value::ObjectPath(p) = path {
         Ok(p.to_owned())
     } else {
         Err(zbus::fdo::Error::Failed(
-            "mpris::trackid is not ObjectPath".to_string(),
+            "mpris:trackid is not ObjectPath".to_string(),
         ))
     }
 }
-
-fn to_fdo_err(e: anyhow::Error) -> zbus::fdo::Error {
-    zbus::fdo::Error::Failed(e.to_string())
-}
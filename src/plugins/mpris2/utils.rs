@@ -1,8 +1,38 @@
+use crate::mpd::{quote, types::MpdState, MpdError, MpdErrorType, MpdStateServer};
+
 use anyhow::Result;
-use log::error;
-use std::{collections::HashMap, time::Duration};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use zbus::fdo;
 use zvariant::{ObjectPath, Value};
 
+/// Translate an error from MPD into a D-Bus error that describes what went wrong
+pub fn to_fdo_err(e: anyhow::Error) -> fdo::Error {
+    let mpd_error = match e.downcast_ref::<MpdError>() {
+        Some(mpd_error) => mpd_error,
+        // Not an error from MPD itself, so the connection is probably broken
+        None => return fdo::Error::Failed(format!("{e:#}")),
+    };
+
+    let msg = mpd_error.to_string();
+    match mpd_error.kind() {
+        MpdErrorType::BadArgument | MpdErrorType::NoExist | MpdErrorType::Exist => {
+            fdo::Error::InvalidArgs(msg)
+        }
+        MpdErrorType::BadPassword | MpdErrorType::Permission => fdo::Error::AccessDenied(msg),
+        MpdErrorType::UnknownCommand => fdo::Error::NotSupported(msg),
+        _ => fdo::Error::Failed(msg),
+    }
+}
+
 pub fn id_to_object_path<'a>(id: impl std::fmt::Display) -> ObjectPath<'a> {
     let path = format!("/org/musicpd/song/{id}");
     ObjectPath::try_from(path).unwrap()
@@ -17,10 +47,101 @@ pub fn object_path_to_id(path: &ObjectPath) -> Option<u64> {
     None
 }
 
+/// An extra MPD tag to put in MPRIS metadata
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagMapping {
+    /// MPD tag, as in `currentsong`
+    pub tag: String,
+    /// Metadata key, which should be namespaced like `xesam:` or `mpd:`
+    pub key: String,
+    /// What to turn the tag into
+    #[serde(default, rename = "type")]
+    pub kind: TagKind,
+}
+
+/// D-Bus type of a metadata entry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagKind {
+    /// First value of the tag
+    #[default]
+    String,
+    /// Every value of the tag
+    Strings,
+    /// Leading number of the tag, so that `3/12` is 3
+    Int,
+    /// Floating point number
+    Float,
+}
+
+/// MPD tags outside the MPRIS spec. They go in the `mpd:` namespace.
+const MPD_TAGS: [(&str, &str, TagKind); 17] = [
+    ("Performer", "mpd:performer", TagKind::Strings),
+    ("Conductor", "mpd:conductor", TagKind::Strings),
+    ("Label", "mpd:label", TagKind::String),
+    ("Last-Modified", "mpd:lastModified", TagKind::String),
+    ("ArtistSort", "mpd:artistSort", TagKind::Strings),
+    ("AlbumSort", "mpd:albumSort", TagKind::String),
+    ("AlbumArtistSort", "mpd:albumArtistSort", TagKind::Strings),
+    ("TitleSort", "mpd:titleSort", TagKind::String),
+    ("ComposerSort", "mpd:composerSort", TagKind::Strings),
+    (
+        "MUSICBRAINZ_ARTISTID",
+        "mpd:musicbrainzArtistId",
+        TagKind::Strings,
+    ),
+    (
+        "MUSICBRAINZ_ALBUMID",
+        "mpd:musicbrainzAlbumId",
+        TagKind::String,
+    ),
+    (
+        "MUSICBRAINZ_ALBUMARTISTID",
+        "mpd:musicbrainzAlbumArtistId",
+        TagKind::Strings,
+    ),
+    (
+        "MUSICBRAINZ_TRACKID",
+        "mpd:musicbrainzTrackId",
+        TagKind::String,
+    ),
+    (
+        "MUSICBRAINZ_RELEASETRACKID",
+        "mpd:musicbrainzReleaseTrackId",
+        TagKind::String,
+    ),
+    (
+        "MUSICBRAINZ_WORKID",
+        "mpd:musicbrainzWorkId",
+        TagKind::String,
+    ),
+    ("OriginalDate", "mpd:originalDate", TagKind::String),
+    ("Date", "mpd:date", TagKind::String),
+];
+
+/// Convert tags of a song (as returned by `currentsong` or `playlistinfo`)
+/// into MPRIS metadata. `music_dir` is needed to make `xesam:url` of files,
+/// `extra` mappings win over the standard ones.
 pub fn to_mpris_metadata<'a>(
     mut i: HashMap<String, Vec<String>>,
+    music_dir: Option<&Path>,
+    extra: &[TagMapping],
 ) -> Result<HashMap<String, Value<'a>>> {
     let mut res = HashMap::new();
+    let mut custom = HashMap::new();
+    for mapping in extra {
+        if let Some(value) = i.get(&mapping.tag) {
+            if let Some(value) = convert(value.clone(), mapping.kind) {
+                custom.insert(mapping.key.clone(), value);
+            } else {
+                error!(
+                    "can't parse metadata tag {} -> {} with value {}",
+                    mapping.tag, mapping.key, value[0]
+                );
+            }
+        }
+    }
 
     let i = &mut i;
     let r = &mut res;
@@ -28,22 +149,33 @@ pub fn to_mpris_metadata<'a>(
         let object_id = id_to_object_path(&id[0]);
         r.insert("mpris:trackid".to_string(), Value::new(object_id));
     }
-    if let Some(length) = i.remove("duration") {
-        let length: f64 = length[0].parse()?;
-        let t = Duration::from_secs_f64(length);
-        r.insert("mpris:length".to_owned(), Value::new(t.as_micros() as u64));
-    }
-    // TODO: Create URI
-    convert_str_tag(i, r, "Album", "xesam:album");
-    convert_str_array_tag(i, r, "AlbumArtist", "xesam:albumArtist");
-    convert_str_array_tag(i, r, "Artist", "xesam:artist");
-    // TODO: Lyrics
-    convert_str_array_tag(i, r, "Comment", "xesam:comment");
-    convert_str_array_tag(i, r, "Composer", "xesam:composer");
-    convert_str_tag(i, r, "Disc", "xesam:discNumber");
-    convert_str_tag(i, r, "Genre", "xesam:genre");
-    convert_str_tag(i, r, "Title", "xesam:title");
-    convert_int_tag(i, r, "Track", "xesam:trackNumber");
+    if let Some(length) = i.remove("duration").or_else(|| i.remove("Time")) {
+        // Leave out a broken length rather than the whole song
+        match parse_duration(&length[0]) {
+            Some(t) => {
+                r.insert("mpris:length".to_owned(), Value::new(t.as_micros() as u64));
+            }
+            None => warn!("can't parse song length {}", length[0]),
+        }
+    }
+    // The first date the song came out
+    let created = i.get("OriginalDate").or_else(|| i.get("Date"));
+    if let Some(date) = created.and_then(|date| to_iso8601(&date[0])) {
+        r.insert("xesam:contentCreated".to_owned(), Value::new(date));
+    }
+    convert_tag(i, r, "Album", "xesam:album", TagKind::String);
+    convert_tag(i, r, "AlbumArtist", "xesam:albumArtist", TagKind::Strings);
+    convert_tag(i, r, "Artist", "xesam:artist", TagKind::Strings);
+    convert_tag(i, r, "BPM", "xesam:audioBPM", TagKind::Int);
+    convert_tag(i, r, "Comment", "xesam:comment", TagKind::Strings);
+    convert_tag(i, r, "Composer", "xesam:composer", TagKind::Strings);
+    convert_tag(i, r, "Disc", "xesam:discNumber", TagKind::Int);
+    convert_tag(i, r, "Genre", "xesam:genre", TagKind::Strings);
+    convert_tag(i, r, "Title", "xesam:title", TagKind::String);
+    convert_tag(i, r, "Track", "xesam:trackNumber", TagKind::Int);
+    for (mpd_key, mpris_key, kind) in MPD_TAGS {
+        convert_tag(i, r, mpd_key, mpris_key, kind);
+    }
     if let Some(mut value) = i.remove("file") {
         let r = value.remove(0);
 
@@ -52,50 +184,209 @@ pub fn to_mpris_metadata<'a>(
         res.entry("xesam:title".to_owned())
             .or_insert_with(|| Value::new(title.to_owned()));
 
-        res.insert("xesam:url".to_owned(), Value::new(r));
+        if let Some(url) = to_url(&r, music_dir) {
+            res.insert("xesam:url".to_owned(), Value::new(url));
+        }
     }
 
+    res.extend(custom);
     Ok(res)
 }
 
-fn convert_str_tag(
+fn convert_tag(
     i: &mut HashMap<String, Vec<String>>,
     res: &mut HashMap<String, Value>,
     mpd_key: &str,
     mpris_key: &str,
+    kind: TagKind,
 ) {
-    if let Some(mut value) = i.remove(mpd_key) {
-        let r = value.remove(0);
-        res.insert(mpris_key.to_owned(), Value::new(r));
+    if let Some(value) = i.remove(mpd_key) {
+        let first = value[0].clone();
+        match convert(value, kind) {
+            Some(r) => {
+                res.insert(mpris_key.to_owned(), r);
+            }
+            None => {
+                error!("can't parse metadata tag {mpd_key} -> {mpris_key} with value {first}")
+            }
+        }
     }
 }
 
-fn convert_str_array_tag(
-    i: &mut HashMap<String, Vec<String>>,
-    res: &mut HashMap<String, Value>,
-    mpd_key: &str,
-    mpris_key: &str,
-) {
-    if let Some(mut value) = i.remove(mpd_key) {
-        value.dedup();
-        res.insert(mpris_key.to_owned(), Value::new(value));
+fn convert<'a>(mut value: Vec<String>, kind: TagKind) -> Option<Value<'a>> {
+    let r = match kind {
+        TagKind::String => Value::new(value.remove(0)),
+        TagKind::Strings => {
+            value.dedup();
+            Value::new(value)
+        }
+        TagKind::Int => Value::new(leading_number::<i32>(&value[0])?),
+        TagKind::Float => Value::new(value[0].trim().parse::<f64>().ok()?),
+    };
+    Some(r)
+}
+
+/// Length of a song from `playlistinfo` or `currentsong`, if it has one
+pub fn song_duration(song: &HashMap<String, Vec<String>>) -> Option<Duration> {
+    let secs = song.get("duration").or_else(|| song.get("Time"))?;
+    parse_duration(&secs[0])
+}
+
+/// Seconds as MPD gives them, like `215.373`
+fn parse_duration(secs: &str) -> Option<Duration> {
+    Duration::try_from_secs_f64(secs.parse().ok()?).ok()
+}
+
+/// Number at the start of a tag, as track and disc numbers may come as `3/12`
+pub fn leading_number<T: FromStr>(value: &str) -> Option<T> {
+    let number = value.split('/').next().unwrap_or_default();
+    number.trim().parse().ok()
+}
+
+/// MPD dates are whatever the tags say, usually `2001` or `2001-02-03`
+fn to_iso8601(date: &str) -> Option<String> {
+    let is_num = |s: &str, len| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+    let mut parts = date.trim().splitn(3, '-');
+    let year = parts.next().filter(|y| is_num(y, 4))?;
+    let month = parts.next().unwrap_or("01");
+    let day = parts.next().map_or("01", |d| d.get(..2).unwrap_or(d));
+    if !is_num(month, 2) || !is_num(day, 2) {
+        return None;
     }
+    Some(format!("{year}-{month}-{day}T00:00:00Z"))
 }
 
-fn convert_int_tag(
-    i: &mut HashMap<String, Vec<String>>,
-    res: &mut HashMap<String, Value>,
-    mpd_key: &str,
-    mpris_key: &str,
-) {
-    if let Some(value) = i.remove(mpd_key) {
-        let value = &value[0];
-        if let Ok(r) = value.parse::<i64>() {
-            res.insert(mpris_key.to_owned(), Value::new(r));
+/// A Unix timestamp as an ISO 8601 date in UTC
+pub fn unix_to_iso8601(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Streams keep their URL, files get a `file://` URL if we know where the
+/// music directory is
+pub fn to_url(uri: &str, music_dir: Option<&Path>) -> Option<String> {
+    if uri.contains("://") {
+        return Some(uri.to_owned());
+    }
+    let path = music_dir?.join(uri);
+    let mut url = String::from("file://");
+    for &b in path.as_os_str().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            url.push(b as char);
         } else {
-            error!("can't parse metadata tag {mpd_key} -> {mpris_key} with value {value}");
+            url += &format!("%{b:02X}");
         }
     }
+    Some(url)
+}
+
+/// Position the next song added to the queue gets
+pub async fn queue_end(mpd: &MpdStateServer) -> Result<u64> {
+    let status = mpd.issue_command("status").await?.field_map();
+    Ok(status
+        .get("playlistlength")
+        .and_then(|len| len[0].parse().ok())
+        .unwrap_or(0))
+}
+
+/// Quote an argument of a MPD command, for D-Bus callers
+pub fn quote_arg(arg: &str) -> fdo::Result<String> {
+    quote(arg).map_err(|e| fdo::Error::InvalidArgs(e.to_string()))
+}
+
+/// The other way around: what MPD calls the file at a `file://` URL in the
+/// music directory. URLs MPD can play itself are kept.
+pub fn from_url(url: &str, state: &MpdState) -> Result<String, String> {
+    let Some((scheme, _)) = url.split_once("://") else {
+        return Err(format!("{url} is not a URL"));
+    };
+    if !scheme.eq_ignore_ascii_case("file") {
+        if state
+            .uri_schemes
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(scheme))
+        {
+            return Ok(url.to_owned());
+        }
+        return Err(format!("MPD can't play {scheme} URLs"));
+    }
+    to_mpd_path(&file_url_to_path(url)?, state.music_directory.as_deref())
+}
+
+/// Local path of a `file://` URL
+pub fn file_url_to_path(url: &str) -> Result<PathBuf, String> {
+    let path = url
+        .split_once("://")
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("file"))
+        .ok_or_else(|| format!("{url} is not a file URL"))?
+        .1;
+    let path = path.strip_prefix("localhost").unwrap_or(path);
+    if !path.starts_with('/') {
+        return Err(format!("Can't open files on other hosts: {url}"));
+    }
+    Ok(PathBuf::from(OsString::from_vec(percent_decode(path))))
+}
+
+/// What MPD calls the file at `path`, which has to be in the music directory
+pub fn to_mpd_path(path: &Path, music_dir: Option<&Path>) -> Result<String, String> {
+    let Some(music_dir) = music_dir else {
+        return Err("Can't open files, the music directory is unknown".to_owned());
+    };
+    // Playlists are full of `../`, resolve them without touching the disk
+    let mut normalized = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => (),
+            c => normalized.push(c),
+        }
+    }
+    let relative = normalized
+        .strip_prefix(music_dir)
+        .map_err(|_| format!("{} is not in the music directory", path.display()))?;
+    // MPD wants the root of its music directory as the empty path
+    relative
+        .to_str()
+        .map(str::to_owned)
+        .ok_or_else(|| format!("{} is not valid UTF-8", path.display()))
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let mut res = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = bytes.clone().take(2).collect::<Vec<_>>();
+            let decoded = std::str::from_utf8(&hex)
+                .ok()
+                .filter(|hex| hex.len() == 2 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(decoded) = decoded {
+                res.push(decoded);
+                bytes.nth(1);
+                continue;
+            }
+        }
+        res.push(b);
+    }
+    res
 }
 
 fn find_filename_from_relpath(i: &str) -> &str {
@@ -105,3 +396,191 @@ fn find_filename_from_relpath(i: &str) -> &str {
         i
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%2Fc"), b"a b/c");
+        assert_eq!(percent_decode("%e2%9C%93"), "\u{2713}".as_bytes());
+        // Broken escapes are kept as they are
+        assert_eq!(percent_decode("100%"), b"100%");
+        assert_eq!(percent_decode("%4"), b"%4");
+        assert_eq!(percent_decode("%zz%+1"), b"%zz%+1");
+    }
+
+    #[test]
+    fn file_urls() {
+        let path = |url| file_url_to_path(url).map(|p| p.to_string_lossy().into_owned());
+        assert_eq!(path("file:///music/a%20b.flac").unwrap(), "/music/a b.flac");
+        assert_eq!(path("FILE://localhost/music/x").unwrap(), "/music/x");
+        assert!(path("file://otherhost/music/x").is_err());
+        assert!(path("http://example.com/x").is_err());
+        assert!(path("/music/x").is_err());
+    }
+
+    #[test]
+    fn mpd_paths() {
+        let music = Some(Path::new("/music"));
+        let mpd_path = |path| to_mpd_path(Path::new(path), music);
+        assert_eq!(mpd_path("/music/Album/01.flac").unwrap(), "Album/01.flac");
+        assert_eq!(
+            mpd_path("/music/Other/../Album/./01.flac").unwrap(),
+            "Album/01.flac"
+        );
+        assert_eq!(mpd_path("/music").unwrap(), "");
+        assert!(mpd_path("/music/../etc/passwd").is_err());
+        assert!(mpd_path("/musicals/x.flac").is_err());
+        assert!(to_mpd_path(Path::new("/music/x.flac"), None).is_err());
+    }
+
+    #[test]
+    fn line_breaks_in_uris() {
+        let state = MpdState {
+            music_directory: Some(PathBuf::from("/music")),
+            uri_schemes: Arc::new(vec!["http".to_owned()]),
+            ..MpdState::offline()
+        };
+        // What OpenUri and PlayNext do with a URI
+        let arg = |uri| from_url(uri, &state).map(|uri| quote_arg(&uri));
+        assert_eq!(
+            arg("file:///music/a%20b.flac").unwrap().unwrap(),
+            r#""a b.flac""#
+        );
+        assert!(matches!(
+            arg("file:///music/a.flac%0Aclear").unwrap(),
+            Err(fdo::Error::InvalidArgs(_))
+        ));
+        assert!(arg("file:///music/a.flac%0D%0Aclear").unwrap().is_err());
+        assert!(arg("http://radio/stream\nclear").unwrap().is_err());
+    }
+
+    #[test]
+    fn durations() {
+        let song =
+            |key: &str, value: &str| HashMap::from([(key.to_owned(), vec![value.to_owned()])]);
+        assert_eq!(
+            song_duration(&song("duration", "1.5")),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            song_duration(&song("Time", "3")),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(song_duration(&song("duration", "1e30")), None);
+        assert_eq!(song_duration(&song("duration", "-1")), None);
+        assert_eq!(song_duration(&song("duration", "nan")), None);
+        assert_eq!(song_duration(&HashMap::new()), None);
+    }
+
+    #[test]
+    fn leading_numbers() {
+        assert_eq!(leading_number::<u32>("1/12"), Some(1));
+        assert_eq!(leading_number::<u32>(" 3 "), Some(3));
+        assert_eq!(leading_number::<i32>("-2/5"), Some(-2));
+        assert_eq!(leading_number::<u32>("/12"), None);
+        assert_eq!(leading_number::<u32>("A1"), None);
+        assert_eq!(leading_number::<u32>(""), None);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(to_iso8601("2024").unwrap(), "2024-01-01T00:00:00Z");
+        assert_eq!(to_iso8601("2024-05").unwrap(), "2024-05-01T00:00:00Z");
+        assert_eq!(to_iso8601(" 2024-05-06 ").unwrap(), "2024-05-06T00:00:00Z");
+        assert_eq!(
+            to_iso8601("2024-05-06T10:00").unwrap(),
+            "2024-05-06T00:00:00Z"
+        );
+        for date in ["24", "2024-5", "2024-05-6", "May 2024", "2024/05/06", ""] {
+            assert_eq!(to_iso8601(date), None, "{date}");
+        }
+
+        assert_eq!(unix_to_iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(unix_to_iso8601(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(unix_to_iso8601(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn metadata() {
+        let song = |tags: &[(&str, &str)]| {
+            let mut song: HashMap<String, Vec<String>> = HashMap::new();
+            for (tag, value) in tags {
+                song.entry(tag.to_string())
+                    .or_default()
+                    .push(value.to_string());
+            }
+            song
+        };
+        let tags = song(&[
+            ("file", "Album/01 - One.flac"),
+            ("Id", "7"),
+            ("duration", "61.5"),
+            ("Track", "1/12"),
+            ("Disc", "2"),
+            ("Date", "2024-05"),
+            ("Artist", "A"),
+            ("Artist", "A"),
+            ("Artist", "B"),
+            ("BPM", "fast"),
+            ("Label", "Label"),
+        ]);
+        let extra = [TagMapping {
+            tag: "Label".to_owned(),
+            key: "xesam:comment".to_owned(),
+            kind: TagKind::Strings,
+        }];
+        let res = to_mpris_metadata(tags, Some(Path::new("/music")), &extra).unwrap();
+        assert_eq!(res["xesam:trackNumber"], Value::new(1));
+        assert_eq!(res["xesam:discNumber"], Value::new(2));
+        assert_eq!(
+            res["xesam:contentCreated"],
+            Value::new("2024-05-01T00:00:00Z")
+        );
+        assert_eq!(res["xesam:artist"], Value::new(vec!["A", "B"]));
+        assert_eq!(res["mpris:length"], Value::new(61_500_000u64));
+        assert_eq!(
+            res["mpris:trackid"],
+            Value::new(ObjectPath::try_from("/org/musicpd/song/7").unwrap())
+        );
+        assert_eq!(res["xesam:title"], Value::new("01 - One.flac"));
+        assert_eq!(
+            res["xesam:url"],
+            Value::new("file:///music/Album/01%20-%20One.flac")
+        );
+        assert_eq!(res["mpd:label"], Value::new("Label"));
+        assert_eq!(res["xesam:comment"], Value::new(vec!["Label"]));
+        assert!(!res.contains_key("xesam:audioBPM"));
+
+        // A broken length leaves out only the length
+        for length in ["soon", "-1", "1e30"] {
+            let tags = song(&[("Title", "T"), ("duration", length)]);
+            let res = to_mpris_metadata(tags, None, &[]).unwrap();
+            assert!(!res.contains_key("mpris:length"), "{length}");
+            assert_eq!(res["xesam:title"], Value::new("T"));
+        }
+    }
+
+    #[test]
+    fn url_round_trip() {
+        let music = Path::new("/music");
+        let url = to_url("Some Album/01 - Ünïcode?.flac", Some(music)).unwrap();
+        assert_eq!(
+            url,
+            "file:///music/Some%20Album/01%20-%20%C3%9Cn%C3%AFcode%3F.flac"
+        );
+        let path = file_url_to_path(&url).unwrap();
+        assert_eq!(
+            to_mpd_path(&path, Some(music)).unwrap(),
+            "Some Album/01 - Ünïcode?.flac"
+        );
+        assert_eq!(
+            to_url("http://radio/stream", None).unwrap(),
+            "http://radio/stream"
+        );
+        assert_eq!(to_url("a.flac", None), None);
+    }
+}
@@ -0,0 +1,234 @@
+//! Keeps long-running tasks alive
+//!
+//! A supervised task is restarted with exponential backoff when it returns an
+//! error or panics. If a critical task keeps failing, the supervisor gives up
+//! and reports it through [`Supervisor::fatal`], so that the process can exit
+//! instead of limping along.
+use anyhow::Result;
+use log::{error, info, warn};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    spawn,
+    sync::mpsc,
+    task::{AbortHandle, JoinError, JoinHandle},
+    time::sleep,
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A task that stayed up this long is considered healthy again
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+/// How many times in a row a critical task may fail before we give up
+const MAX_CRITICAL_FAILURES: u32 = 5;
+
+/// What a supervised task is doing right now
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TaskStatus {
+    /// Up and running
+    Running,
+    /// Failed, waiting to be restarted
+    Restarting,
+    /// Finished on its own
+    Finished,
+    /// Failed too many times, not restarted anymore
+    Failed,
+}
+
+impl Display for TaskStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            TaskStatus::Running => "running",
+            TaskStatus::Restarting => "restarting",
+            TaskStatus::Finished => "finished",
+            TaskStatus::Failed => "failed",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Health report of a supervised task
+#[derive(Clone, Debug)]
+pub struct TaskHealth {
+    /// What the task is doing
+    pub status: TaskStatus,
+    /// Whether the process should exit if this task can't be kept alive
+    pub critical: bool,
+    /// How many times the task has been restarted
+    pub restarts: u32,
+    /// Why the task failed the last time
+    pub last_error: Option<String>,
+}
+
+/// Spawns tasks and restarts them when they fail
+pub struct Supervisor {
+    health: Arc<Mutex<BTreeMap<String, TaskHealth>>>,
+    fatal_tx: mpsc::UnboundedSender<String>,
+    fatal_rx: mpsc::UnboundedReceiver<String>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        let (fatal_tx, fatal_rx) = mpsc::unbounded_channel();
+        Supervisor {
+            health: Arc::default(),
+            fatal_tx,
+            fatal_rx,
+        }
+    }
+}
+
+impl Supervisor {
+    /// Create a supervisor without any task
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run the task built by `f` and keep it alive. `f` is called again to
+    /// build a fresh task every time the previous one failed.
+    pub fn spawn<F, Fut>(&self, name: &str, critical: bool, mut f: F) -> JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let name = name.to_owned();
+        let health = self.health.clone();
+        let fatal_tx = self.fatal_tx.clone();
+        set_health(&health, &name, |h| {
+            *h = TaskHealth {
+                status: TaskStatus::Running,
+                critical,
+                restarts: 0,
+                last_error: None,
+            }
+        });
+
+        spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            let mut failures = 0;
+            loop {
+                let started_at = Instant::now();
+                // Run every attempt in its own task, so that panics are caught.
+                // The attempt goes away with us if we're aborted.
+                let attempt = spawn(f());
+                let _guard = AbortOnDrop(attempt.abort_handle());
+                let err = match attempt.await {
+                    Ok(Ok(())) => {
+                        info!("Task {name} finished");
+                        set_health(&health, &name, |h| h.status = TaskStatus::Finished);
+                        return;
+                    }
+                    Ok(Err(e)) => format!("{e:#}"),
+                    Err(e) => describe_join_error(e),
+                };
+
+                if started_at.elapsed() >= HEALTHY_AFTER {
+                    backoff = INITIAL_BACKOFF;
+                    failures = 0;
+                }
+                failures += 1;
+
+                if critical && failures >= MAX_CRITICAL_FAILURES {
+                    error!(
+                        "Critical task {name} failed {failures} times in a row, giving up: {err}"
+                    );
+                    set_health(&health, &name, |h| {
+                        h.status = TaskStatus::Failed;
+                        h.last_error = Some(err);
+                    });
+                    fatal_tx.send(name).ok();
+                    return;
+                }
+
+                error!(
+                    "Task {name} failed, restarting in {}s: {err}",
+                    backoff.as_secs()
+                );
+                set_health(&health, &name, |h| {
+                    h.status = TaskStatus::Restarting;
+                    h.last_error = Some(err);
+                });
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+
+                set_health(&health, &name, |h| {
+                    h.status = TaskStatus::Running;
+                    h.restarts += 1;
+                });
+            }
+        })
+    }
+
+    /// Health of every supervised task, by name
+    pub fn health(&self) -> BTreeMap<String, TaskHealth> {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// Log the health of every supervised task
+    pub fn report(&self) {
+        for (name, h) in self.health() {
+            let msg = format!(
+                "Task {name}: {} ({} restarts{})",
+                h.status,
+                h.restarts,
+                h.last_error
+                    .map(|e| format!(", last error: {e}"))
+                    .unwrap_or_default()
+            );
+            if h.status == TaskStatus::Running || h.status == TaskStatus::Finished {
+                info!("{msg}");
+            } else {
+                warn!("{msg}");
+            }
+        }
+    }
+
+    /// Wait until a critical task fails for good. Returns its name.
+    pub async fn fatal(&mut self) -> Option<String> {
+        self.fatal_rx.recv().await
+    }
+}
+
+/// Aborts a task when dropped
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn set_health(
+    health: &Mutex<BTreeMap<String, TaskHealth>>,
+    name: &str,
+    f: impl FnOnce(&mut TaskHealth),
+) {
+    let mut health = health.lock().unwrap();
+    let h = health.entry(name.to_owned()).or_insert(TaskHealth {
+        status: TaskStatus::Running,
+        critical: false,
+        restarts: 0,
+        last_error: None,
+    });
+    f(h);
+}
+
+fn describe_join_error(e: JoinError) -> String {
+    if !e.is_panic() {
+        return "task cancelled".to_owned();
+    }
+    let panic = e.into_panic();
+    let msg = if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown reason".to_owned()
+    };
+    format!("panicked: {msg}")
+}
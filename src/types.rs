@@ -1,10 +1,41 @@
+//! Events shared between the state server and its consumers
+use crate::mpd::types::MpdStateChanged;
+
+/// What changed in MPD state. Broadcast by [`crate::mpd::MpdStateServer`];
+/// the new value can be read from its latest state snapshot.
 #[derive(Clone, Debug)]
 pub enum PlayerStateChange {
+    /// MPD went online or offline
+    Connection,
+    /// Playing, paused or stopped
     Playback,
+    /// Repeat/single mode
     Loop,
+    /// Random mode
     Shuffle,
+    /// Mixer volume
     Volume,
+    /// Current song
     Song,
+    /// Album art of the current song, which may show up a while after the
+    /// song changed
+    AlbumArt,
+    /// Rating or play count of the current song, set by another client
+    Stickers,
+    /// Lyrics of the current song, which may also show up late
+    Lyrics,
+    /// The current line of synced lyrics
+    LyricLine,
+    /// Song that will be played next
     NextSong,
+    /// The queue
     Tracklist,
+    /// Audio outputs, or whether they're enabled
+    Outputs,
+    /// MPD partitions were added or removed
+    Partitions,
+    /// MPD reported a change in a subsystem, after the state was updated
+    /// for it. Only subsystems the state server follows itself or someone
+    /// asked for with [`crate::mpd::MpdStateServer::subscribe`] are reported.
+    Mpd(MpdStateChanged),
 }